@@ -0,0 +1,484 @@
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::{env, fs, str::FromStr};
+
+/// Runtime configuration, read from the toml file named by `BRASS_CONFIG`
+/// (`brass.toml` by default). Every value can be overridden by a `BRASS_*`
+/// environment variable, see [`Config::load`].
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    pub database: Database,
+    pub session: Session,
+    pub password: Password,
+    pub mail: Mail,
+    pub account: Account,
+    pub two_factor: TwoFactor,
+    pub login: Login,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub host: String,
+    pub port: u16,
+    pub workers: usize,
+    pub static_dir: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Database {
+    /// Address of the SurrealDB server, or `memory` for an in-memory database.
+    pub uri: String,
+    pub username: String,
+    pub password: String,
+    pub namespace: String,
+    pub database: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Session {
+    /// Base64 encoded key of at least 64 bytes used to sign the session cookie.
+    /// When unset the key is read from `key_file`, which is created on first boot.
+    pub key: Option<String>,
+    /// Key the current one replaced, cookies signed with it are still accepted.
+    pub previous_key: Option<String>,
+    pub key_file: String,
+    /// Replace the key in `key_file` once it is older than this, 0 never does.
+    pub rotate_days: u64,
+    /// How long cookies signed by the replaced key are accepted after a rotation.
+    pub grace_hours: u64,
+    /// A session ends when it wasn't used for this long.
+    pub idle_hours: u64,
+    /// A session ends this long after login, no matter how much it is used.
+    pub lifetime_hours: u64,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: SameSite,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl FromStr for SameSite {
+    type Err = String;
+    fn from_str(s: &str) -> Result<SameSite, String> {
+        match s.to_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err("expected strict, lax or none".into()),
+        }
+    }
+}
+
+/// Argon2id costs for new password hashes and what new passwords have to look
+/// like. Hashes made with other costs are replaced on the next successful login.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Password {
+    pub memory_kib: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+    /// Length in characters.
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Rejects passwords found in the bundled list of breached passwords.
+    pub reject_breached: bool,
+}
+
+/// How mails like the email verification leave the server.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mail {
+    pub transport: Transport,
+    /// Sender of every mail, like `brass <noreply@example.com>`.
+    pub from: String,
+    /// Where the server is reachable, links in mails start with it.
+    pub base_url: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    /// Left empty when the SMTP server doesn't want a login.
+    pub smtp_username: String,
+    pub smtp_password: String,
+    /// Upgrades the SMTP connection with STARTTLS, turning it off is only meant
+    /// for a relay on localhost.
+    pub smtp_starttls: bool,
+    /// Directory the `file` transport writes mails into.
+    pub drop_dir: String,
+    /// How long a verification link stays valid.
+    pub verify_hours: u64,
+    /// How long a password reset link stays valid.
+    pub reset_minutes: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Smtp,
+    /// Writes every mail as an `.eml` file into `drop_dir`.
+    File,
+    /// Keeps mails in memory and logs them.
+    Memory,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Transport, String> {
+        match s.to_lowercase().as_str() {
+            "smtp" => Ok(Transport::Smtp),
+            "file" => Ok(Transport::File),
+            "memory" => Ok(Transport::Memory),
+            _ => Err("expected smtp, file or memory".into()),
+        }
+    }
+}
+
+impl Default for Mail {
+    fn default() -> Mail {
+        Mail {
+            transport: Transport::File,
+            from: "brass <noreply@localhost>".into(),
+            base_url: "http://localhost:8080".into(),
+            smtp_host: "localhost".into(),
+            smtp_port: 587,
+            smtp_username: String::new(),
+            smtp_password: String::new(),
+            smtp_starttls: true,
+            drop_dir: "mail".into(),
+            verify_hours: 48,
+            reset_minutes: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwoFactor {
+    /// Shown next to the account in authenticator apps.
+    pub issuer: String,
+    /// Base64 encoded 32 byte key TOTP secrets are encrypted with. When unset
+    /// the key is read from `key_file`, which is created on first boot.
+    pub key: Option<String>,
+    pub key_file: String,
+    /// How long the code can be entered after the password was accepted.
+    pub pending_minutes: u64,
+}
+
+impl Default for TwoFactor {
+    fn default() -> TwoFactor {
+        TwoFactor {
+            issuer: "brass".into(),
+            key: None,
+            key_file: "totp.key".into(),
+            pending_minutes: 5,
+        }
+    }
+}
+
+/// How many failed logins are let through before further attempts are turned
+/// away with `429`. Every failure past the limit doubles the lockout.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Login {
+    /// Failed logins from one address before it is locked out.
+    pub ip_attempts: u32,
+    /// Failed logins for one account before it is locked out, from any address.
+    pub account_attempts: u32,
+    /// Lockout after the first failure past the limit.
+    pub lockout_seconds: u64,
+    /// Longest a lockout gets, no matter how many failures there were.
+    pub max_lockout_minutes: u64,
+    /// Failures are forgotten once there wasn't one for this long.
+    pub window_minutes: u64,
+}
+
+impl Default for Login {
+    fn default() -> Login {
+        Login {
+            ip_attempts: 20,
+            account_attempts: 5,
+            lockout_seconds: 30,
+            max_lockout_minutes: 60,
+            window_minutes: 15,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Account {
+    /// What happens to the messages of a deleted account.
+    pub deleted_messages: Retention,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Retention {
+    /// Removes the text and leaves a marker where the message was.
+    #[default]
+    Tombstone,
+    /// Keeps the text but no longer tells who wrote it.
+    Anonymize,
+}
+
+impl FromStr for Retention {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Retention, String> {
+        match s.to_lowercase().as_str() {
+            "tombstone" => Ok(Retention::Tombstone),
+            "anonymize" => Ok(Retention::Anonymize),
+            _ => Err("expected tombstone or anonymize".into()),
+        }
+    }
+}
+
+impl Default for Password {
+    fn default() -> Password {
+        Password {
+            memory_kib: 19 * 1024,
+            time_cost: 2,
+            parallelism: 1,
+            min_length: 8,
+            max_length: 256,
+            reject_breached: true,
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server {
+            host: "0.0.0.0".into(),
+            port: 8080,
+            workers: 4,
+            static_dir: "www/dist".into(),
+        }
+    }
+}
+
+impl Default for Database {
+    fn default() -> Database {
+        Database {
+            uri: "localhost:8000".into(),
+            username: "root".into(),
+            password: "root".into(),
+            namespace: "joe".into(),
+            database: "database".into(),
+        }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Session {
+        Session {
+            key: None,
+            previous_key: None,
+            key_file: "session.key".into(),
+            rotate_days: 0,
+            grace_hours: 24 * 7,
+            idle_hours: 24 * 3,
+            lifetime_hours: 24 * 30,
+            secure: true,
+            http_only: true,
+            same_site: SameSite::Lax,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Config> {
+        let path = env::var("BRASS_CONFIG").ok();
+        let mut config: Config = match fs::read_to_string(path.as_deref().unwrap_or("brass.toml")) {
+            Ok(v) => toml::from_str(&v)?,
+            // the default file is optional, one that was asked for isn't
+            Err(_) if path.is_none() => Config::default(),
+            Err(err) => return Err(anyhow!("couldn't read {} : {err}", path.unwrap())),
+        };
+        set(&mut config.server.host, "BRASS_HOST")?;
+        set(&mut config.server.port, "BRASS_PORT")?;
+        set(&mut config.server.workers, "BRASS_WORKERS")?;
+        set(&mut config.server.static_dir, "BRASS_STATIC_DIR")?;
+        set(&mut config.database.uri, "BRASS_DB_URI")?;
+        set(&mut config.database.username, "BRASS_DB_USERNAME")?;
+        set(&mut config.database.password, "BRASS_DB_PASSWORD")?;
+        set(&mut config.database.namespace, "BRASS_DB_NAMESPACE")?;
+        set(&mut config.database.database, "BRASS_DB_DATABASE")?;
+        if let Ok(v) = env::var("BRASS_SESSION_KEY") {
+            config.session.key = Some(v);
+        }
+        if let Ok(v) = env::var("BRASS_SESSION_PREVIOUS_KEY") {
+            config.session.previous_key = Some(v);
+        }
+        set(&mut config.session.key_file, "BRASS_SESSION_KEY_FILE")?;
+        set(&mut config.session.rotate_days, "BRASS_SESSION_ROTATE_DAYS")?;
+        set(&mut config.session.grace_hours, "BRASS_SESSION_GRACE_HOURS")?;
+        set(&mut config.session.idle_hours, "BRASS_SESSION_IDLE_HOURS")?;
+        set(
+            &mut config.session.lifetime_hours,
+            "BRASS_SESSION_LIFETIME_HOURS",
+        )?;
+        set(&mut config.session.secure, "BRASS_COOKIE_SECURE")?;
+        set(&mut config.session.http_only, "BRASS_COOKIE_HTTP_ONLY")?;
+        set(&mut config.session.same_site, "BRASS_COOKIE_SAME_SITE")?;
+        set(&mut config.password.memory_kib, "BRASS_PASSWORD_MEMORY_KIB")?;
+        set(&mut config.password.time_cost, "BRASS_PASSWORD_TIME_COST")?;
+        set(
+            &mut config.password.parallelism,
+            "BRASS_PASSWORD_PARALLELISM",
+        )?;
+        set(&mut config.password.min_length, "BRASS_PASSWORD_MIN_LENGTH")?;
+        set(&mut config.password.max_length, "BRASS_PASSWORD_MAX_LENGTH")?;
+        set(
+            &mut config.password.reject_breached,
+            "BRASS_PASSWORD_REJECT_BREACHED",
+        )?;
+        set(&mut config.mail.transport, "BRASS_MAIL_TRANSPORT")?;
+        set(&mut config.mail.from, "BRASS_MAIL_FROM")?;
+        set(&mut config.mail.base_url, "BRASS_MAIL_BASE_URL")?;
+        set(&mut config.mail.smtp_host, "BRASS_SMTP_HOST")?;
+        set(&mut config.mail.smtp_port, "BRASS_SMTP_PORT")?;
+        set(&mut config.mail.smtp_username, "BRASS_SMTP_USERNAME")?;
+        set(&mut config.mail.smtp_password, "BRASS_SMTP_PASSWORD")?;
+        set(&mut config.mail.smtp_starttls, "BRASS_SMTP_STARTTLS")?;
+        set(&mut config.mail.drop_dir, "BRASS_MAIL_DROP_DIR")?;
+        set(&mut config.mail.verify_hours, "BRASS_MAIL_VERIFY_HOURS")?;
+        set(&mut config.mail.reset_minutes, "BRASS_MAIL_RESET_MINUTES")?;
+        set(
+            &mut config.account.deleted_messages,
+            "BRASS_ACCOUNT_DELETED_MESSAGES",
+        )?;
+        set(&mut config.two_factor.issuer, "BRASS_TWO_FACTOR_ISSUER")?;
+        if let Ok(v) = env::var("BRASS_TWO_FACTOR_KEY") {
+            config.two_factor.key = Some(v);
+        }
+        set(&mut config.two_factor.key_file, "BRASS_TWO_FACTOR_KEY_FILE")?;
+        set(
+            &mut config.two_factor.pending_minutes,
+            "BRASS_TWO_FACTOR_PENDING_MINUTES",
+        )?;
+        set(&mut config.login.ip_attempts, "BRASS_LOGIN_IP_ATTEMPTS")?;
+        set(
+            &mut config.login.account_attempts,
+            "BRASS_LOGIN_ACCOUNT_ATTEMPTS",
+        )?;
+        set(
+            &mut config.login.lockout_seconds,
+            "BRASS_LOGIN_LOCKOUT_SECONDS",
+        )?;
+        set(
+            &mut config.login.max_lockout_minutes,
+            "BRASS_LOGIN_MAX_LOCKOUT_MINUTES",
+        )?;
+        set(
+            &mut config.login.window_minutes,
+            "BRASS_LOGIN_WINDOW_MINUTES",
+        )?;
+        config.validate()?;
+        Ok(config)
+    }
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.server.workers == 0 {
+            return Err(anyhow!("server.workers has to be at least 1"));
+        }
+        if self.database.uri.is_empty() {
+            return Err(anyhow!("database.uri is empty"));
+        }
+        if self.database.namespace.is_empty() || self.database.database.is_empty() {
+            return Err(anyhow!(
+                "database.namespace and database.database can't be empty"
+            ));
+        }
+        for key in [&self.session.key, &self.session.previous_key]
+            .into_iter()
+            .flatten()
+        {
+            if decode_key(key)?.len() < 64 {
+                return Err(anyhow!("session keys have to be at least 64 bytes long"));
+            }
+        }
+        if self.session.idle_hours == 0 || self.session.lifetime_hours == 0 {
+            return Err(anyhow!(
+                "session.idle_hours and session.lifetime_hours can't be 0"
+            ));
+        }
+        if self.session.key.is_none() && self.session.key_file.is_empty() {
+            return Err(anyhow!(
+                "either session.key or session.key_file has to be set"
+            ));
+        }
+        if matches!(self.session.same_site, SameSite::None) && !self.session.secure {
+            return Err(anyhow!(
+                "session.same_site = \"none\" requires session.secure"
+            ));
+        }
+        if self.password.min_length == 0 || self.password.min_length > self.password.max_length {
+            return Err(anyhow!(
+                "password.min_length has to be between 1 and password.max_length"
+            ));
+        }
+        crate::cryption::argon(&self.password)?;
+        if self.mail.from.parse::<lettre::message::Mailbox>().is_err() {
+            return Err(anyhow!("mail.from isn't a valid address"));
+        }
+        if let Some(key) = &self.two_factor.key {
+            if decode_key(key)?.len() != 32 {
+                return Err(anyhow!("two_factor.key has to be 32 bytes long"));
+            }
+        }
+        if self.two_factor.key.is_none() && self.two_factor.key_file.is_empty() {
+            return Err(anyhow!(
+                "either two_factor.key or two_factor.key_file has to be set"
+            ));
+        }
+        if self.two_factor.pending_minutes == 0 {
+            return Err(anyhow!("two_factor.pending_minutes can't be 0"));
+        }
+        if self.mail.verify_hours == 0 || self.mail.reset_minutes == 0 {
+            return Err(anyhow!(
+                "mail.verify_hours and mail.reset_minutes can't be 0"
+            ));
+        }
+        if self.login.ip_attempts == 0 || self.login.account_attempts == 0 {
+            return Err(anyhow!(
+                "login.ip_attempts and login.account_attempts can't be 0"
+            ));
+        }
+        if self.login.lockout_seconds == 0
+            || self.login.max_lockout_minutes == 0
+            || self.login.window_minutes == 0
+        {
+            return Err(anyhow!(
+                "login.lockout_seconds, login.max_lockout_minutes and login.window_minutes can't be 0"
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn decode_key(key: &str) -> anyhow::Result<Vec<u8>> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD
+        .decode(key.trim())
+        .map_err(|err| anyhow!("key isn't valid base64 : {err}"))
+}
+
+fn set<T: FromStr>(field: &mut T, var: &str) -> anyhow::Result<()>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Ok(v) = env::var(var) {
+        *field = v
+            .parse()
+            .map_err(|err| anyhow!("{var} has an invalid value : {err}"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use crate::{config, utils};
+use actix_session::{config::PersistentSession, storage::CookieSessionStore, SessionMiddleware};
+use actix_web::{
+    cookie::{self, time::Duration, Cookie, CookieJar, Key},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderValue},
+};
+use anyhow::anyhow;
+use base64::Engine;
+use std::{
+    env, fs,
+    time::{Duration as StdDuration, SystemTime},
+};
+
+pub const COOKIE_NAME: &str = "id";
+
+/// The key session cookies are signed with, plus the one it replaced while
+/// cookies signed by that are still accepted.
+#[derive(Clone)]
+pub struct Keys {
+    pub current: Key,
+    pub previous: Option<Key>,
+}
+
+impl Keys {
+    /// Takes the keys from the configuration, or else from `session.key_file`.
+    /// The file is created on first boot and rotated when it is older than
+    /// `session.rotate_days` or the server is started with `--rotate-session-key`.
+    pub fn load(config: &config::Session) -> anyhow::Result<Keys> {
+        if let Some(key) = &config.key {
+            let previous = match &config.previous_key {
+                Some(v) => Some(Key::from(&config::decode_key(v)?)),
+                None => None,
+            };
+            return Ok(Keys {
+                current: Key::from(&config::decode_key(key)?),
+                previous,
+            });
+        }
+        let previous_file = format!("{}.previous", config.key_file);
+        match age(&config.key_file) {
+            None => {
+                log::info!("creating session key in {}", config.key_file);
+                write_key(&config.key_file, &Key::generate())?;
+            }
+            Some(age)
+                if env::args().any(|a| a == "--rotate-session-key")
+                    || (config.rotate_days > 0
+                        && age > StdDuration::from_secs(config.rotate_days * 24 * 60 * 60)) =>
+            {
+                log::info!("rotating session key in {}", config.key_file);
+                // written instead of renamed, so its age tells when the rotation happened
+                write_key(&previous_file, &read_key(&config.key_file)?)?;
+                write_key(&config.key_file, &Key::generate())?;
+            }
+            Some(_) => (),
+        }
+        let grace = StdDuration::from_secs(config.grace_hours * 60 * 60);
+        let previous = match age(&previous_file) {
+            Some(age) if age < grace => Some(read_key(&previous_file)?),
+            _ => None,
+        };
+        Ok(Keys {
+            current: read_key(&config.key_file)?,
+            previous,
+        })
+    }
+
+    /// Re-encrypts a session cookie made with the previous key using the current
+    /// one, so the session middleware accepts it. Returns the cookie that has to
+    /// be handed back to the client.
+    pub fn upgrade(
+        &self,
+        config: &config::Session,
+        req: &mut ServiceRequest,
+    ) -> Option<Cookie<'static>> {
+        let previous = self.previous.as_ref()?;
+        // parsed by hand, `HttpRequest::cookies` caches what it saw the first time
+        let mut cookies: Vec<Cookie<'static>> = req
+            .headers()
+            .get_all(header::COOKIE)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|c| Cookie::parse_encoded(c.trim().to_string()).ok())
+            .collect();
+        let original = cookies.iter_mut().find(|c| c.name() == COOKIE_NAME)?;
+        let mut jar = CookieJar::new();
+        jar.add_original(original.clone());
+        if jar.private(&self.current).get(COOKIE_NAME).is_some() {
+            return None;
+        }
+        let plain = jar.private(previous).get(COOKIE_NAME)?;
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.current)
+            .add(build(config, plain.value().to_string()));
+        let upgraded = jar.delta().next()?.clone();
+        original.set_value(upgraded.value().to_string());
+        let header = cookies
+            .iter()
+            .map(|c| c.stripped().encoded().to_string())
+            .collect::<Vec<String>>()
+            .join("; ");
+        req.headers_mut()
+            .insert(header::COOKIE, HeaderValue::from_str(&header).ok()?);
+        Some(upgraded)
+    }
+}
+
+/// Hands an upgraded cookie back, unless the session middleware set a new one.
+pub fn reissue<B>(res: &mut ServiceResponse<B>, cookie: Cookie<'static>) {
+    let prefix = format!("{COOKIE_NAME}=");
+    let set = res
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .any(|v| v.to_str().map(|v| v.starts_with(&prefix)).unwrap_or(false));
+    if !set {
+        if let Ok(v) = HeaderValue::from_str(&cookie.encoded().to_string()) {
+            res.headers_mut().append(header::SET_COOKIE, v);
+        }
+    }
+}
+
+pub fn middleware(keys: &Keys, config: &config::Session) -> SessionMiddleware<CookieSessionStore> {
+    SessionMiddleware::builder(CookieSessionStore::default(), keys.current.clone())
+        .cookie_name(COOKIE_NAME.into())
+        .cookie_secure(config.secure)
+        .cookie_http_only(config.http_only)
+        .cookie_same_site(same_site(config.same_site))
+        // customize session and cookie expiration
+        .session_lifecycle(PersistentSession::default().session_ttl(ttl(config)))
+        .build()
+}
+
+fn build(config: &config::Session, value: String) -> Cookie<'static> {
+    Cookie::build(COOKIE_NAME, value)
+        .path("/")
+        .secure(config.secure)
+        .http_only(config.http_only)
+        .same_site(same_site(config.same_site))
+        .max_age(ttl(config))
+        .finish()
+}
+
+// the cookie lives as long as the server side session may
+fn ttl(config: &config::Session) -> Duration {
+    Duration::hours(config.lifetime_hours as i64)
+}
+
+fn same_site(same_site: config::SameSite) -> cookie::SameSite {
+    match same_site {
+        config::SameSite::Strict => cookie::SameSite::Strict,
+        config::SameSite::Lax => cookie::SameSite::Lax,
+        config::SameSite::None => cookie::SameSite::None,
+    }
+}
+
+fn age(path: &str) -> Option<StdDuration> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(
+        SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default(),
+    )
+}
+
+fn read_key(path: &str) -> anyhow::Result<Key> {
+    let key = config::decode_key(&fs::read_to_string(path)?)?;
+    if key.len() < 64 {
+        return Err(anyhow!("{path} has to hold at least 64 bytes"));
+    }
+    Ok(Key::from(&key))
+}
+
+fn write_key(path: &str, key: &Key) -> anyhow::Result<()> {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(key.master());
+    utils::write_secret(path, encoded.as_bytes())
+}
@@ -1,32 +1,174 @@
+use crate::{config, utils};
+use actix_web::web;
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
 use anyhow::anyhow;
-use pbkdf2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Pbkdf2,
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString},
+    Algorithm, Argon2, Params, Version,
 };
-use rand::Rng;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use pbkdf2::Pbkdf2;
+use std::fs;
 
-pub fn new(input: &[u8]) -> anyhow::Result<String> {
-    let salt = SaltString::generate(&mut OsRng);
-    match Pbkdf2.hash_password(input, &salt) {
-        Ok(str) => Ok(str.to_string()),
-        Err(err) => Err(anyhow!("{:?}", err)),
-    }
+const NONCE_BYTES: usize = 12;
+
+/// Encrypts what has to be read back later, like TOTP secrets, with AES-256-GCM.
+#[derive(Clone)]
+pub struct Secrets {
+    cipher: Aes256Gcm,
+}
+
+/// Hashes with Argon2id, the costs come from the `[password]` configuration.
+/// It runs on the blocking thread pool so it doesn't stall the workers.
+pub async fn new(input: &[u8], config: &config::Password) -> anyhow::Result<String> {
+    let (input, argon) = (input.to_vec(), argon(config)?);
+    web::block(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        match argon.hash_password(&input, &salt) {
+            Ok(str) => Ok(str.to_string()),
+            Err(err) => Err(anyhow!("{:?}", err)),
+        }
+    })
+    .await?
 }
-pub fn verify(input: &[u8], hash: String) -> anyhow::Result<bool> {
-    let parsed_hash = match PasswordHash::new(&hash) {
-        Ok(v) => v,
-        Err(err) => return Err(anyhow!("{:?}", err)),
+/// Verifies Argon2id hashes as well as the PBKDF2 ones older accounts still
+/// have, on the blocking thread pool like `new`.
+pub async fn verify(input: &[u8], hash: String) -> anyhow::Result<bool> {
+    let input = input.to_vec();
+    web::block(move || {
+        let parsed_hash = match PasswordHash::new(&hash) {
+            Ok(v) => v,
+            Err(err) => return Err(anyhow!("{:?}", err)),
+        };
+        match parsed_hash.verify_password(&[&Argon2::default(), &Pbkdf2], &input) {
+            Ok(_) => Ok(true),
+            Err(_) => Ok(false),
+        }
+    })
+    .await?
+}
+/// Tells whether a hash was made with another algorithm or other costs than
+/// `new` would use now.
+pub fn outdated(hash: &str, config: &config::Password) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(hash) else {
+        return true;
     };
-    match Pbkdf2.verify_password(input, &parsed_hash) {
-        Ok(_) => Ok(true),
-        Err(_) => Ok(false),
+    if parsed_hash.algorithm != argon2::ARGON2ID_IDENT {
+        return true;
+    }
+    match Params::try_from(&parsed_hash) {
+        Ok(params) => {
+            params.m_cost() != config.memory_kib
+                || params.t_cost() != config.time_cost
+                || params.p_cost() != config.parallelism
+        }
+        Err(_) => true,
+    }
+}
+
+pub fn argon(config: &config::Password) -> anyhow::Result<Argon2<'static>> {
+    let params = Params::new(
+        config.memory_kib,
+        config.time_cost,
+        config.parallelism,
+        None,
+    )
+    .map_err(|err| anyhow!("invalid password hashing costs : {err}"))?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+impl Secrets {
+    /// Takes the key from `two_factor.key`, or else from `two_factor.key_file`,
+    /// which is created on first boot.
+    pub fn load(config: &config::TwoFactor) -> anyhow::Result<Secrets> {
+        let key = match &config.key {
+            Some(v) => config::decode_key(v)?,
+            None => {
+                if fs::metadata(&config.key_file).is_err() {
+                    log::info!("creating two factor key in {}", config.key_file);
+                    let key = Aes256Gcm::generate_key(&mut OsRng);
+                    utils::write_secret(&config.key_file, STANDARD.encode(key).as_bytes())?;
+                }
+                config::decode_key(&fs::read_to_string(&config.key_file)?)?
+            }
+        };
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| anyhow!("the two factor key has to be 32 bytes long"))?;
+        Ok(Secrets { cipher })
+    }
+    /// Seals `plain` to `context`, like the id of the record it is stored in,
+    /// so it can't be moved to another one.
+    pub fn encrypt(&self, plain: &[u8], context: &str) -> anyhow::Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plain,
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("couldn't encrypt secret"))?;
+        Ok(STANDARD.encode([nonce.as_slice(), &sealed].concat()))
+    }
+    pub fn decrypt(&self, sealed: &str, context: &str) -> anyhow::Result<Vec<u8>> {
+        let bytes = STANDARD.decode(sealed)?;
+        if bytes.len() < NONCE_BYTES {
+            return Err(anyhow!("encrypted secret is too short"));
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_BYTES);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("couldn't decrypt secret, was the two factor key changed?"))
     }
 }
 
-pub fn get_id(index: usize) -> Vec<char> {
-    let mut s = vec![];
-    for _ in 0..index {
-        s.push(rand::thread_rng().gen_range(48..126) as u8 as char);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn argon_hashes_verify() {
+        let config = config::Password::default();
+        let hash = new(b"correct horse", &config).await.unwrap();
+        assert!(verify(b"correct horse", hash.clone()).await.unwrap());
+        assert!(!verify(b"wrong horse", hash.clone()).await.unwrap());
+        assert!(!outdated(&hash, &config));
+
+        let cheaper = config::Password {
+            time_cost: 1,
+            ..config::Password::default()
+        };
+        assert!(outdated(&hash, &cheaper));
+    }
+
+    // hashes older accounts were made with still log in, and get upgraded
+    #[actix_web::test]
+    async fn pbkdf2_hashes_verify() {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Pbkdf2
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+        assert!(hash.starts_with("$pbkdf2-sha256$"));
+        assert!(verify(b"correct horse", hash.clone()).await.unwrap());
+        assert!(!verify(b"wrong horse", hash.clone()).await.unwrap());
+        assert!(outdated(&hash, &config::Password::default()));
+    }
+
+    #[actix_web::test]
+    async fn broken_hashes_fail() {
+        assert!(verify(b"correct horse", "not a hash".into()).await.is_err());
+        assert!(outdated("not a hash", &config::Password::default()));
     }
-    s
 }
@@ -0,0 +1,227 @@
+use crate::{
+    config,
+    error::Result,
+    table::{Message, Permission, Role},
+};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+mod surreal;
+
+pub use surreal::{MemoryDatabase, RemoteDatabase};
+
+/// Everything the routes and the chat server need from storage. Records are
+/// referred to by their `table:id` strings, so no backend types leak out.
+#[async_trait]
+pub trait Database: Send + Sync {
+    /// Creates an unverified account and returns the token that verifies its
+    /// email. Fails when the email or the username is taken, ignoring case.
+    async fn signup(&self, email: String, username: String, password: String) -> Result<String>;
+    /// Marks the account the verification token was made for as verified.
+    async fn verify_email(&self, token: String) -> Result<()>;
+    /// Returns a new verification token for the unverified account with this
+    /// email, if there is one.
+    async fn resend_verification(&self, email: String) -> Result<Option<String>>;
+    /// Returns a password reset token for the account with this email, if there is one.
+    async fn forgot_password(&self, email: String) -> Result<Option<String>>;
+    /// Sets the password of the account the reset token was made for and ends
+    /// all of its sessions, whose record ids are returned.
+    async fn reset_password(&self, token: String, password: String) -> Result<Vec<String>>;
+    /// Sets a new password after checking the current one and ends every other
+    /// session of the account, whose record ids are returned.
+    async fn change_password(
+        &self,
+        sid: String,
+        current: String,
+        password: String,
+    ) -> Result<Vec<String>>;
+    /// Checks the password and returns a verification token that moves the
+    /// account to `email` once it is used.
+    async fn change_email(&self, sid: String, password: String, email: String) -> Result<String>;
+    /// Checks the password and deletes the account behind `sid`. It is removed
+    /// from its chats and its messages are handled as `account.deleted_messages`
+    /// says. Returns the record ids of its sessions and the chats it was in,
+    /// with the message stored about it in groups that are left behind.
+    async fn delete_account(
+        &self,
+        sid: String,
+        password: String,
+    ) -> Result<(Vec<String>, Vec<(String, Option<Message>)>)>;
+    /// Checks the password and opens a new session for the account, or asks
+    /// for a second factor first when it has one.
+    async fn login(
+        &self,
+        email: String,
+        password: String,
+        user_agent: String,
+        ip: String,
+    ) -> Result<Login>;
+    /// Finishes a login that waits for a TOTP or recovery code and returns the
+    /// id of the new session.
+    async fn login_two_factor(
+        &self,
+        token: String,
+        code: String,
+        user_agent: String,
+        ip: String,
+    ) -> Result<String>;
+    /// Checks the password and starts enrolling a new TOTP secret. Returns the
+    /// secret and the email of the account, it is used once confirmed.
+    async fn enroll_two_factor(&self, sid: String, password: String) -> Result<(Vec<u8>, String)>;
+    /// Turns two factor authentication on if `code` matches the enrolled secret
+    /// and returns fresh recovery codes.
+    async fn confirm_two_factor(&self, sid: String, code: String) -> Result<Vec<String>>;
+    async fn disable_two_factor(&self, sid: String, password: String, code: String) -> Result<()>;
+    /// Changes what is given of the settings of the account behind `sid`, an
+    /// empty public key removes it.
+    async fn update_account(
+        &self,
+        sid: String,
+        contacts_only: Option<bool>,
+        public_key: Option<String>,
+    ) -> Result<()>;
+    /// Asks the user called `username` to become a contact of `user`. When
+    /// they asked first, both are contacts right away.
+    async fn request_contact(&self, user: String, username: String) -> Result<Request>;
+    /// Accepts or declines the request `requester` sent to `user`.
+    async fn answer_contact(&self, user: String, requester: String, accept: bool) -> Result<()>;
+    /// Takes back the request `user` sent to `recipient`.
+    async fn cancel_contact(&self, user: String, recipient: String) -> Result<()>;
+    async fn remove_contact(&self, user: String, contact: String) -> Result<()>;
+    /// Returns the contacts of `user` and the requests it sent and got as json.
+    async fn list_contacts(&self, user: String) -> Result<String>;
+    /// Blocks or unblocks `other` for `user`. Blocking ends their contact and
+    /// drops the requests between them.
+    async fn block_user(&self, user: String, other: String, block: bool) -> Result<()>;
+    /// Mutes or unmutes a chat `user` is a member of for it.
+    async fn mute_chat(&self, chat_id: String, user: String, mute: bool) -> Result<()>;
+    /// Returns the users `user` blocked and the chats it muted.
+    async fn get_filters(&self, user: String) -> Result<(Vec<String>, Vec<String>)>;
+    /// Returns the account behind a session as json.
+    async fn get_data(&self, sid: String) -> Result<String>;
+    /// Returns the direct chat between two users, or of a user with themself,
+    /// and creates it the first time. Groups are made with `create_group`.
+    async fn get_chat(&self, users: Vec<String>) -> Result<String>;
+    /// Returns the id of the chat between `owner` and the user called `reciver`.
+    async fn direct_chat(&self, owner: String, reciver: String) -> Result<String>;
+    /// Creates a group of `owner` and `members` and returns its id together
+    /// with the message announcing it, which is already stored in the chat.
+    async fn create_group(
+        &self,
+        owner: String,
+        title: String,
+        topic: Option<String>,
+        avatar: Option<String>,
+        members: Vec<String>,
+    ) -> Result<(String, Message)>;
+    /// Changes what is given of the title, topic and avatar of a group `user`
+    /// is a member of, empty topics and avatars are removed. The group
+    /// functions below return the message about the change they stored.
+    async fn update_group(
+        &self,
+        chat_id: String,
+        user: String,
+        title: Option<String>,
+        topic: Option<String>,
+        avatar: Option<String>,
+    ) -> Result<Message>;
+    async fn add_member(&self, chat_id: String, user: String, member: String) -> Result<Message>;
+    async fn remove_member(&self, chat_id: String, user: String, member: String)
+        -> Result<Message>;
+    /// Removes `user` from a group, which is deleted once nobody is left in it.
+    /// When the owner leaves, the highest ranking member takes over.
+    async fn leave_group(&self, chat_id: String, user: String) -> Result<Message>;
+    /// Gives `member` a new role, only the owner may. Making someone else the
+    /// owner makes `user` an admin.
+    async fn set_role(
+        &self,
+        chat_id: String,
+        user: String,
+        member: String,
+        role: Role,
+    ) -> Result<Message>;
+    /// Pins or unpins a message of the chat.
+    async fn pin_message(
+        &self,
+        chat_id: String,
+        user: String,
+        message: String,
+        pin: bool,
+    ) -> Result<Message>;
+    /// Removes the text of a message, like `Retention::Tombstone` does. Others'
+    /// messages need `Permission::DeleteOthers`.
+    async fn delete_message(&self, chat_id: String, user: String, message: String) -> Result<()>;
+    /// Fails unless `user` is a member of the chat whose role allows `permission`.
+    async fn check_permission(
+        &self,
+        chat_id: String,
+        user: String,
+        permission: Permission,
+    ) -> Result<()>;
+    /// Returns members and a page of messages of a chat `user` is a member of as json.
+    async fn get_messages(
+        &self,
+        chat_id: String,
+        user: String,
+        before: Option<String>,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<String>;
+    async fn get_members(&self, chat_id: String) -> Result<Vec<String>>;
+    /// Returns every chat of `user` together with its members.
+    async fn get_rooms(&self, user: String) -> Result<Vec<(String, Vec<String>)>>;
+    async fn get_username(&self, sid: String) -> Result<String>;
+    async fn insert_to_chat(&self, chat_id: String, msg: Message) -> Result<String>;
+    async fn get_id(&self, sid: String) -> Result<String>;
+    /// Returns the unexpired sessions of the account behind `sid` as json.
+    async fn list_sessions(&self, sid: String) -> Result<String>;
+    /// Ends the session with the record id `id`, if it belongs to the same account as `sid`.
+    async fn revoke_session(&self, sid: String, id: String) -> Result<()>;
+    /// Ends every session of the account behind `sid`, including `sid` itself,
+    /// and returns their record ids.
+    async fn revoke_sessions(&self, sid: String) -> Result<Vec<String>>;
+    /// Returns the record id of the session behind `sid`.
+    async fn get_session(&self, sid: String) -> Result<String>;
+    /// Ends the session behind `sid` and returns its record id.
+    async fn logout(&self, sid: String) -> Result<String>;
+}
+
+pub enum Login {
+    /// Id of the new session.
+    Session(String),
+    /// Token to finish the login with once the second factor is given.
+    TwoFactor(String),
+}
+
+pub enum Request {
+    /// Id of the user who got the request.
+    Sent(String),
+    /// Id of the user who had sent a request first and is a contact now.
+    Accepted(String),
+}
+
+/// Adds the `user:` prefix to a user id given without it, like the ids
+/// `get_data` returns, so they match the ones the chat server keeps.
+pub fn user_id(id: String) -> String {
+    match id.contains(':') {
+        true => id,
+        false => format!("user:{id}"),
+    }
+}
+
+/// Like `user_id`, for chat ids given without their `chat:` prefix.
+pub fn chat_id(id: String) -> String {
+    match id.contains(':') {
+        true => id,
+        false => format!("chat:{id}"),
+    }
+}
+
+/// Connects to the configured SurrealDB server, or starts an in-memory
+/// database when the uri is `memory`.
+pub async fn connect(config: &config::Config) -> anyhow::Result<Arc<dyn Database>> {
+    if config.database.uri == "memory" {
+        return Ok(Arc::new(MemoryDatabase::memory(config).await?));
+    }
+    Ok(Arc::new(RemoteDatabase::new(config).await?))
+}
@@ -0,0 +1,2031 @@
+use super::{Database, Login, Request};
+use crate::error::{ApiError, Result};
+use crate::table::{
+    Account, Chat, ChatEvent, ChatKind, ContactRequest, Message, Permission, Role, Session, Token,
+    DELETED_USER,
+};
+use crate::{config, cryption, token, totp};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use surrealdb::{
+    engine::local::{Db, Mem},
+    engine::remote::ws::{Client, Ws},
+    opt::auth::Root,
+    sql::Thing,
+    Connection, Surreal,
+};
+
+pub struct SurrealDatabase<C: Connection> {
+    pub con: Surreal<C>,
+    idle: Duration,
+    lifetime: Duration,
+    verify: Duration,
+    reset: Duration,
+    pending: Duration,
+    secrets: cryption::Secrets,
+    password: config::Password,
+    retention: config::Retention,
+}
+
+/// Talks to a SurrealDB server over websockets.
+pub type RemoteDatabase = SurrealDatabase<Client>;
+/// Keeps everything in memory of the running process, nothing survives a restart.
+pub type MemoryDatabase = SurrealDatabase<Db>;
+
+#[derive(Deserialize, Debug)]
+struct Record {
+    id: Thing,
+}
+
+#[derive(Deserialize, Debug)]
+struct SessionRecord {
+    id: Thing,
+    #[serde(flatten)]
+    session: Session,
+}
+
+#[derive(Deserialize, Debug)]
+struct Room {
+    id: Thing,
+    members: Vec<Thing>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatRecord {
+    id: Thing,
+    #[serde(default)]
+    kind: ChatKind,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    topic: Option<String>,
+    #[serde(default)]
+    avatar: Option<String>,
+    members: Vec<Thing>,
+    #[serde(default)]
+    roles: BTreeMap<String, Role>,
+    #[serde(default)]
+    pinned: Vec<String>,
+    messages: Vec<Message>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ContactRecord {
+    id: Thing,
+    username: String,
+    #[serde(default)]
+    public_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Privacy {
+    id: Thing,
+    #[serde(default)]
+    contacts_only: bool,
+    #[serde(default)]
+    contacts: Vec<Thing>,
+}
+
+/// Whose messages a user doesn't want and which chats it muted.
+#[derive(Deserialize, Debug, Default)]
+struct Filters {
+    #[serde(default)]
+    blocked: Vec<Thing>,
+    #[serde(default)]
+    muted: Vec<Thing>,
+}
+
+/// Who is in a chat and with which role.
+#[derive(Deserialize, Debug)]
+struct Membership {
+    #[serde(default)]
+    kind: ChatKind,
+    members: Vec<Thing>,
+    #[serde(default)]
+    roles: BTreeMap<String, Role>,
+}
+
+impl Membership {
+    fn role(&self, user: &Thing) -> Role {
+        self.roles
+            .get(&user.to_string())
+            .copied()
+            .unwrap_or_default()
+    }
+    fn allow(&self, user: &Thing, permission: Permission) -> Result<()> {
+        let allowed = match self.kind {
+            // nobody runs a direct chat, both may write and pin
+            ChatKind::Direct => matches!(permission, Permission::Send | Permission::Pin),
+            ChatKind::Group => self.role(user).can(permission),
+        };
+        if !allowed {
+            return Err(ApiError::Forbidden(permission));
+        }
+        Ok(())
+    }
+    /// The roles once `user` is gone, and who owns the group from then on if
+    /// it was `user`.
+    fn succession(&self, user: &Thing) -> (BTreeMap<String, Role>, Option<&Thing>) {
+        let mut roles = self.roles.clone();
+        roles.remove(&user.to_string());
+        if self.role(user) != Role::Owner {
+            return (roles, None);
+        }
+        // the group goes to whoever ranks highest, and has been in it the longest
+        let heir = self
+            .members
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| *m != user)
+            .max_by_key(|(i, m)| (self.role(m), std::cmp::Reverse(*i)))
+            .map(|(_, m)| m);
+        if let Some(heir) = heir {
+            roles.insert(heir.to_string(), Role::Owner);
+        }
+        (roles, heir)
+    }
+}
+
+const EMAIL_INDEX: &str = "user_email";
+const USERNAME_INDEX: &str = "user_username";
+const HISTORY_LIMIT: usize = 50;
+const HISTORY_LIMIT_MAX: usize = 100;
+const RECOVERY_CODES: usize = 10;
+
+impl RemoteDatabase {
+    pub async fn new(config: &config::Config) -> anyhow::Result<RemoteDatabase> {
+        let db = &config.database;
+        let con = Surreal::new::<Ws>(db.uri.as_str()).await?;
+        con.signin(Root {
+            username: &db.username,
+            password: &db.password,
+        })
+        .await?;
+        con.use_ns(&db.namespace).use_db(&db.database).await?;
+        let db = SurrealDatabase::with(con, config)?;
+        db.define_unique().await?;
+        Ok(db)
+    }
+}
+
+impl MemoryDatabase {
+    pub async fn memory(config: &config::Config) -> anyhow::Result<MemoryDatabase> {
+        let db = &config.database;
+        let con = Surreal::new::<Mem>(()).await?;
+        con.use_ns(&db.namespace).use_db(&db.database).await?;
+        let db = SurrealDatabase::with(con, config)?;
+        db.define_unique().await?;
+        Ok(db)
+    }
+}
+
+#[async_trait]
+impl<C: Connection> Database for SurrealDatabase<C> {
+    async fn signup(&self, email: String, username: String, password: String) -> Result<String> {
+        // older accounts may have been stored with capitals in their email
+        if self.email_taken(&email, None).await? {
+            return Err(ApiError::AccountExists);
+        }
+        let mut result = self
+            .con
+            .query("SELECT id FROM user WHERE string::lowercase(username) = $username")
+            .bind(("username", username.to_lowercase()))
+            .await?;
+        let obj: Option<Thing> = result.take((0, "id"))?;
+        if obj.is_some() {
+            return Err(ApiError::UsernameTaken);
+        }
+        let created: Record = self
+            .con
+            .create("user")
+            .content(Account {
+                username,
+                passhash: cryption::new(password.as_bytes(), &self.password).await?,
+                chats: vec![],
+                email,
+                picture: "binary".into(),
+                verified: false,
+                totp: None,
+                totp_pending: None,
+                totp_step: None,
+                recovery_codes: vec![],
+                contacts: vec![],
+                contacts_only: false,
+                public_key: None,
+                blocked: vec![],
+                muted: vec![],
+            })
+            .await
+            .map_err(unique)?;
+        self.create_token(token::Kind::Verify, &created.id, None)
+            .await
+    }
+    async fn verify_email(&self, token: String) -> Result<()> {
+        let record = self
+            .take_token(token::Kind::Verify, &token, self.verify)
+            .await?;
+        let user = string_into_thing(&record.user)?;
+        match record.email {
+            Some(email) => {
+                // somebody may have signed up with it since the change was asked for
+                if self.email_taken(&email, Some(&user)).await? {
+                    return Err(ApiError::AccountExists);
+                }
+                self.con
+                    .query("UPDATE $user SET email = $email, verified = true")
+                    .bind(("user", user))
+                    .bind(("email", email))
+                    .await?
+                    .check()
+                    .map_err(unique)?;
+            }
+            None => {
+                self.con
+                    .query("UPDATE $user SET verified = true")
+                    .bind(("user", user))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+    async fn resend_verification(&self, email: String) -> Result<Option<String>> {
+        let mut result = self
+            .con
+            .query(
+                "SELECT id FROM user WHERE string::lowercase(email) = $email AND verified = false",
+            )
+            .bind(("email", email.to_lowercase()))
+            .await?;
+        let user: Option<Thing> = result.take((0, "id"))?;
+        match user {
+            Some(v) => Ok(Some(
+                self.create_token(token::Kind::Verify, &v, None).await?,
+            )),
+            None => Ok(None),
+        }
+    }
+    async fn forgot_password(&self, email: String) -> Result<Option<String>> {
+        let mut result = self
+            .con
+            .query("SELECT id FROM user WHERE string::lowercase(email) = $email")
+            .bind(("email", email.to_lowercase()))
+            .await?;
+        let user: Option<Thing> = result.take((0, "id"))?;
+        match user {
+            Some(v) => Ok(Some(self.create_token(token::Kind::Reset, &v, None).await?)),
+            None => Ok(None),
+        }
+    }
+    async fn reset_password(&self, token: String, password: String) -> Result<Vec<String>> {
+        let record = self
+            .take_token(token::Kind::Reset, &token, self.reset)
+            .await?;
+        let user = string_into_thing(&record.user)?;
+        self.set_password(&user, &password).await?;
+        // the link arrived, so the email works
+        let mut result = self
+            .con
+            .query("UPDATE $user SET verified = true")
+            .query("DELETE token WHERE user = $id AND kind = $kind")
+            .query("DELETE session WHERE user = $id RETURN BEFORE")
+            .bind(("user", user.clone()))
+            .bind(("id", user.to_string()))
+            .bind(("kind", token::Kind::Reset))
+            .await?;
+        let deleted: Vec<SessionRecord> = result.take(2)?;
+        log::info!(
+            "password of {user} was reset, ended {} sessions",
+            deleted.len()
+        );
+        Ok(deleted.iter().map(|r| r.id.to_string()).collect())
+    }
+    async fn change_password(
+        &self,
+        sid: String,
+        current: String,
+        password: String,
+    ) -> Result<Vec<String>> {
+        let session = self.find_session(sid).await?;
+        let user = string_into_thing(&session.session.user)?;
+        self.check_password(&user, &current).await?;
+        self.set_password(&user, &password).await?;
+        let mut result = self
+            .con
+            .query("DELETE token WHERE user = $id AND kind = $kind")
+            .query("DELETE session WHERE user = $id AND id != $current RETURN BEFORE")
+            .bind(("id", user.to_string()))
+            .bind(("kind", token::Kind::Reset))
+            .bind(("current", session.id))
+            .await?;
+        let deleted: Vec<SessionRecord> = result.take(1)?;
+        log::info!(
+            "password of {user} was changed, ended {} other sessions",
+            deleted.len()
+        );
+        Ok(deleted.iter().map(|r| r.id.to_string()).collect())
+    }
+    async fn change_email(&self, sid: String, password: String, email: String) -> Result<String> {
+        let user = self.resolve(sid).await?;
+        self.check_password(&user, &password).await?;
+        if self.email_taken(&email, Some(&user)).await? {
+            return Err(ApiError::AccountExists);
+        }
+        self.create_token(token::Kind::Verify, &user, Some(email))
+            .await
+    }
+    async fn delete_account(
+        &self,
+        sid: String,
+        password: String,
+    ) -> Result<(Vec<String>, Vec<(String, Option<Message>)>)> {
+        let user = self.resolve(sid).await?;
+        self.check_password(&user, &password).await?;
+        let name = self.username_of(&user).await?;
+        let mut result = self
+            .con
+            .query(
+                "SELECT id, kind, members, roles, messages FROM chat WHERE members CONTAINS $user",
+            )
+            .bind(("user", user.clone()))
+            .await?;
+        let records: Vec<ChatRecord> = result.take(0)?;
+        let mut chats = vec![];
+        for record in records {
+            let owner = user.to_string();
+            let mut messages = record.messages;
+            for message in messages.iter_mut().filter(|m| m.owner == owner) {
+                message.owner = DELETED_USER.into();
+                if self.retention == config::Retention::Tombstone {
+                    message.text.clear();
+                    message.deleted = true;
+                }
+            }
+            let membership = Membership {
+                kind: record.kind,
+                members: record.members,
+                roles: record.roles,
+            };
+            if membership.members.iter().all(|m| m == &user) {
+                self.con
+                    .query("DELETE $chat")
+                    .bind(("chat", record.id.clone()))
+                    .await?;
+                chats.push((record.id.to_string(), None));
+                continue;
+            }
+            let (roles, heir) = membership.succession(&user);
+            // the others in a group learn the user is gone, like when it leaves
+            let mut notice = None;
+            if membership.kind == ChatKind::Group {
+                let mut text = format!("{name} deleted their account");
+                if let Some(heir) = heir {
+                    text = format!(
+                        "{text}, {} owns the group now",
+                        self.username_of(heir).await?
+                    );
+                }
+                let msg = Message::event(ChatEvent::Left, text, DELETED_USER.into());
+                messages.push(msg.clone());
+                notice = Some(msg);
+            }
+            self.detach(&record.id, &user, roles).await?;
+            self.con
+                .query("UPDATE $chat SET messages = $messages")
+                .bind(("chat", record.id.clone()))
+                .bind(("messages", messages))
+                .await?;
+            chats.push((record.id.to_string(), notice));
+        }
+        let mut result = self
+            .con
+            .query("DELETE session WHERE user = $id RETURN BEFORE")
+            .query("DELETE token WHERE user = $id")
+            .query("DELETE contact_request WHERE sender = $id OR recipient = $id")
+            .query("UPDATE user SET contacts -= $user WHERE contacts CONTAINS $user")
+            .query("UPDATE user SET blocked -= $user WHERE blocked CONTAINS $user")
+            .query("DELETE $user")
+            .bind(("id", user.to_string()))
+            .bind(("user", user.clone()))
+            .await?;
+        let deleted: Vec<SessionRecord> = result.take(0)?;
+        log::info!(
+            "account {user} was deleted, it was in {} chats",
+            chats.len()
+        );
+        Ok((deleted.iter().map(|r| r.id.to_string()).collect(), chats))
+    }
+    async fn login(
+        &self,
+        email: String,
+        password: String,
+        user_agent: String,
+        ip: String,
+    ) -> Result<Login> {
+        let mut result = self
+            .con
+            .query(
+                "select passhash,id,verified,totp from user where (string::lowercase(email) = $email)",
+            )
+            .bind(("email", email.to_lowercase()))
+            .await?;
+        let account: Option<String> = result.take((0, "passhash"))?;
+        if let Some(passhash) = account {
+            if cryption::verify(password.as_bytes(), passhash.clone()).await? {
+                let user: Option<Thing> = result.take((0, "id"))?;
+                let verified: Option<bool> = result.take((0, "verified"))?;
+                if verified == Some(false) {
+                    return Err(ApiError::EmailNotVerified);
+                }
+                let totp: Option<String> = result.take((0, "totp"))?;
+                match user {
+                    Some(v) => {
+                        if cryption::outdated(&passhash, &self.password) {
+                            self.set_password(&v, &password).await?;
+                        }
+                        if totp.is_some() {
+                            let token = self.create_token(token::Kind::TwoFactor, &v, None).await?;
+                            return Ok(Login::TwoFactor(token));
+                        }
+                        Ok(Login::Session(
+                            self.create_session(v.to_string(), user_agent, ip).await?,
+                        ))
+                    }
+                    None => Err(anyhow!("account without an id").into()),
+                }
+            } else {
+                Err(ApiError::InvalidCredentials)
+            }
+        } else {
+            Err(ApiError::InvalidCredentials)
+        }
+    }
+    async fn login_two_factor(
+        &self,
+        token: String,
+        code: String,
+        user_agent: String,
+        ip: String,
+    ) -> Result<String> {
+        // a wrong code uses up the token too, guessing needs the password every time
+        let record = self
+            .take_token(token::Kind::TwoFactor, &token, self.pending)
+            .await?;
+        let user = string_into_thing(&record.user)?;
+        self.check_code(&user, &code).await?;
+        self.create_session(record.user, user_agent, ip).await
+    }
+    async fn enroll_two_factor(&self, sid: String, password: String) -> Result<(Vec<u8>, String)> {
+        let user = self.resolve(sid).await?;
+        self.check_password(&user, &password).await?;
+        let mut result = self
+            .con
+            .query("SELECT totp, email FROM $user")
+            .bind(("user", user.clone()))
+            .await?;
+        let enabled: Option<String> = result.take((0, "totp"))?;
+        if enabled.is_some() {
+            return Err(ApiError::TwoFactorEnabled);
+        }
+        let email: Option<String> = result.take((0, "email"))?;
+        let secret = totp::secret();
+        self.con
+            .query("UPDATE $user SET totp_pending = $secret")
+            .bind(("user", user.clone()))
+            .bind(("secret", self.secrets.encrypt(&secret, &user.to_string())?))
+            .await?;
+        Ok((secret, email.unwrap_or_default()))
+    }
+    async fn confirm_two_factor(&self, sid: String, code: String) -> Result<Vec<String>> {
+        let user = self.resolve(sid).await?;
+        let mut result = self
+            .con
+            .query("SELECT totp_pending FROM $user")
+            .bind(("user", user.clone()))
+            .await?;
+        let pending: Option<String> = result.take((0, "totp_pending"))?;
+        let Some(sealed) = pending else {
+            return Err(ApiError::BadRequest(
+                "start enrolling two factor authentication first".into(),
+            ));
+        };
+        let secret = self.secrets.decrypt(&sealed, &user.to_string())?;
+        let Some(step) = totp::check(&secret, &code, None) else {
+            return Err(ApiError::InvalidCode);
+        };
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| totp::recovery_code()).collect();
+        let hashes: Vec<String> = codes
+            .iter()
+            .map(|c| token::hash(&totp::normalize(c)))
+            .collect();
+        self.con
+            .query(
+                "UPDATE $user SET totp = $secret, totp_pending = NONE, totp_step = $step, \
+                 recovery_codes = $codes",
+            )
+            .bind(("user", user.clone()))
+            .bind(("secret", sealed))
+            .bind(("step", step))
+            .bind(("codes", hashes))
+            .await?;
+        log::info!("two factor authentication of {user} was turned on");
+        Ok(codes)
+    }
+    async fn disable_two_factor(&self, sid: String, password: String, code: String) -> Result<()> {
+        let user = self.resolve(sid).await?;
+        self.check_password(&user, &password).await?;
+        self.check_code(&user, &code).await?;
+        self.con
+            .query(
+                "UPDATE $user SET totp = NONE, totp_pending = NONE, totp_step = NONE, \
+                 recovery_codes = []",
+            )
+            .bind(("user", user.clone()))
+            .await?;
+        log::info!("two factor authentication of {user} was turned off");
+        Ok(())
+    }
+    async fn update_account(
+        &self,
+        sid: String,
+        contacts_only: Option<bool>,
+        public_key: Option<String>,
+    ) -> Result<()> {
+        let user = self.resolve(sid).await?;
+        let mut changes = serde_json::Map::new();
+        if let Some(v) = contacts_only {
+            changes.insert("contacts_only".into(), json!(v));
+        }
+        if let Some(v) = public_key {
+            changes.insert("public_key".into(), json!((!v.is_empty()).then_some(v)));
+        }
+        if changes.is_empty() {
+            return Err(ApiError::BadRequest("nothing to change".into()));
+        }
+        self.con
+            .query("UPDATE $user MERGE $changes")
+            .bind(("user", user))
+            .bind(("changes", changes))
+            .await?
+            .check()?;
+        Ok(())
+    }
+    async fn request_contact(&self, user: String, username: String) -> Result<Request> {
+        let user = user_thing(&user)?;
+        let other = string_into_thing(&self.get_user_id(username).await?)?;
+        if other == user {
+            return Err(ApiError::BadRequest("you can't add yourself".into()));
+        }
+        if self.blocked(&user, &other).await? {
+            return Err(ApiError::Blocked);
+        }
+        if self.are_contacts(&user, &other).await? {
+            return Err(ApiError::BadRequest("you are contacts already".into()));
+        }
+        if self.take_request(&other, &user).await? {
+            self.connect(&user, &other).await?;
+            return Ok(Request::Accepted(other.to_string()));
+        }
+        let mut result = self
+            .con
+            .query(
+                "SELECT id FROM contact_request WHERE sender = $sender AND recipient = $recipient",
+            )
+            .bind(("sender", user.to_string()))
+            .bind(("recipient", other.to_string()))
+            .await?;
+        let sent: Option<Thing> = result.take((0, "id"))?;
+        if sent.is_some() {
+            return Err(ApiError::BadRequest("the request was sent already".into()));
+        }
+        let _created: Record = self
+            .con
+            .create("contact_request")
+            .content(ContactRequest {
+                sender: user.to_string(),
+                recipient: other.to_string(),
+                created: Utc::now().to_rfc3339(),
+            })
+            .await?;
+        Ok(Request::Sent(other.to_string()))
+    }
+    async fn answer_contact(&self, user: String, requester: String, accept: bool) -> Result<()> {
+        let (user, requester) = (user_thing(&user)?, user_thing(&requester)?);
+        if !self.take_request(&requester, &user).await? {
+            return Err(ApiError::NotFound("contact request"));
+        }
+        if accept {
+            self.connect(&user, &requester).await?;
+        }
+        Ok(())
+    }
+    async fn cancel_contact(&self, user: String, recipient: String) -> Result<()> {
+        let (user, recipient) = (user_thing(&user)?, user_thing(&recipient)?);
+        if !self.take_request(&user, &recipient).await? {
+            return Err(ApiError::NotFound("contact request"));
+        }
+        Ok(())
+    }
+    async fn remove_contact(&self, user: String, contact: String) -> Result<()> {
+        let (user, contact) = (user_thing(&user)?, user_thing(&contact)?);
+        if !self.are_contacts(&user, &contact).await? {
+            return Err(ApiError::NotFound("contact"));
+        }
+        self.con
+            .query("UPDATE $user SET contacts -= $contact")
+            .query("UPDATE $contact SET contacts -= $user")
+            .bind(("user", user))
+            .bind(("contact", contact))
+            .await?
+            .check()?;
+        Ok(())
+    }
+    async fn list_contacts(&self, user: String) -> Result<String> {
+        let user = user_thing(&user)?;
+        let contacts = self.contacts_of(&user).await?;
+        let mut result = self
+            .con
+            .query("SELECT * FROM contact_request WHERE recipient = $user ORDER BY created")
+            .query("SELECT * FROM contact_request WHERE sender = $user ORDER BY created")
+            .bind(("user", user.to_string()))
+            .await?;
+        let incoming: Vec<ContactRequest> = result.take(0)?;
+        let outgoing: Vec<ContactRequest> = result.take(1)?;
+        let incoming = incoming.into_iter().map(|r| (r.sender, r.created));
+        let outgoing = outgoing.into_iter().map(|r| (r.recipient, r.created));
+        let incoming = self.describe_requests(incoming.collect()).await?;
+        let outgoing = self.describe_requests(outgoing.collect()).await?;
+        Ok(json!({
+            "contacts" : contacts,
+            "incoming" : incoming,
+            "outgoing" : outgoing
+        })
+        .to_string())
+    }
+    async fn block_user(&self, user: String, other: String, block: bool) -> Result<()> {
+        let (user, other) = (user_thing(&user)?, user_thing(&other)?);
+        if other == user {
+            return Err(ApiError::BadRequest("you can't block yourself".into()));
+        }
+        let filters = self.filters(&user).await?;
+        if !block {
+            if !filters.blocked.contains(&other) {
+                return Err(ApiError::NotFound("blocked user"));
+            }
+            self.con
+                .query("UPDATE $user SET blocked -= $other")
+                .bind(("user", user))
+                .bind(("other", other))
+                .await?
+                .check()?;
+            return Ok(());
+        }
+        if !self.exsists(&other).await? {
+            return Err(ApiError::NotFound("user"));
+        }
+        // they stop being contacts, and requests between them are dropped
+        self.con
+            .query("UPDATE $user SET blocked = array::union(blocked OR [], [$other])")
+            .query("UPDATE $user SET contacts -= $other WHERE contacts CONTAINS $other")
+            .query("UPDATE $other SET contacts -= $user WHERE contacts CONTAINS $user")
+            .query("DELETE contact_request WHERE (sender = $a AND recipient = $b) OR (sender = $b AND recipient = $a)")
+            .bind(("user", &user))
+            .bind(("other", &other))
+            .bind(("a", user.to_string()))
+            .bind(("b", other.to_string()))
+            .await?
+            .check()?;
+        Ok(())
+    }
+    async fn mute_chat(&self, chat_id: String, user: String, mute: bool) -> Result<()> {
+        let (chat, user) = (chat_thing(&chat_id)?, user_thing(&user)?);
+        self.membership(&chat, &user).await?;
+        let query = match mute {
+            true => "UPDATE $user SET muted = array::union(muted OR [], [$chat])",
+            false => "UPDATE $user SET muted -= $chat WHERE muted CONTAINS $chat",
+        };
+        self.con
+            .query(query)
+            .bind(("user", user))
+            .bind(("chat", chat))
+            .await?
+            .check()?;
+        Ok(())
+    }
+    async fn get_filters(&self, user: String) -> Result<(Vec<String>, Vec<String>)> {
+        let filters = self.filters(&user_thing(&user)?).await?;
+        let ids = |v: Vec<Thing>| v.iter().map(|v| v.to_string()).collect();
+        Ok((ids(filters.blocked), ids(filters.muted)))
+    }
+    async fn get_data(&self, sid: String) -> Result<String> {
+        let user = self.resolve(sid).await?;
+        let mut result = self
+            .con
+            .query("SELECT username,picture,chats,id FROM $user")
+            .bind(("user", user.clone()))
+            .await?;
+        let account: Option<String> = result.take((0, "username"))?;
+        match account {
+            Some(username) => {
+                let picture: Option<String> = result.take("picture")?;
+                let chats: Option<Vec<Thing>> = result.take("chats")?;
+                let chats: Option<Vec<String>> =
+                    chats.map(|c| c.iter().map(|c| c.to_string()).collect());
+                let id_unfor: Option<Thing> = result.take("id")?;
+                let id: String = match id_unfor {
+                    Some(v) => v.id.to_raw(),
+                    None => {
+                        return Err(ApiError::NotFound("account"));
+                    }
+                };
+                let contacts = self.contacts_of(&user).await?;
+                let filters = self.filters(&user).await?;
+                let mut blocked = vec![];
+                for other in filters.blocked {
+                    // the other account may be gone
+                    if let Ok(username) = self.username_of(&other).await {
+                        blocked.push(json!({ "id" : other.to_string(), "username" : username }));
+                    }
+                }
+                let muted: Vec<String> = filters.muted.iter().map(|c| c.to_string()).collect();
+                Ok(json!({
+                    "username" : username,
+                    "picture" : picture,
+                    "chats" : chats,
+                    "contacts" : contacts,
+                    "blocked" : blocked,
+                    "muted" : muted,
+                    "id" : id
+                })
+                .to_string())
+            }
+            None => Err(ApiError::NotFound("account")),
+        }
+    }
+    async fn get_chat(&self, users: Vec<String>) -> Result<String> {
+        let mut members: Vec<Thing> = Vec::new();
+        for user in users.iter() {
+            let user = user_thing(user)?;
+            if !self.exsists(&user).await? {
+                return Err(ApiError::NotFound("user"));
+            }
+            if !members.contains(&user) {
+                members.push(user);
+            }
+        }
+        if members.is_empty() {
+            return Err(ApiError::BadRequest("a chat needs members".into()));
+        }
+        if members.len() > 2 {
+            return Err(ApiError::BadRequest(
+                "direct chats are between two users, create a group instead".into(),
+            ));
+        }
+        members.sort();
+        self.direct_allowed(&members).await?;
+        let chat = direct_key(&members);
+        if self.chat_exists(&chat).await? {
+            return Ok(chat.to_string());
+        }
+        // chats from before the canonical key don't have it as their id
+        let mut result = self
+            .con
+            .query("SELECT id FROM chat WHERE kind != 'group' AND string::startsWith(meta::id(id), 'dm_') = false AND members CONTAINSALL $members AND array::len(array::distinct(members)) = $count LIMIT 1")
+            .bind(("members", members.clone()))
+            .bind(("count", members.len()))
+            .await?;
+        let existing: Option<Thing> = result.take((0, "id"))?;
+        if let Some(v) = existing {
+            return Ok(v.to_string());
+        }
+        if let Err(err) = self.create_chat(Some(&chat), Chat::default()).await {
+            // a concurrent request for the same pair created it first
+            if !self.chat_exists(&chat).await? {
+                return Err(err);
+            }
+        }
+        self.chat_init(members, &chat).await?;
+        Ok(chat.to_string())
+    }
+    async fn direct_chat(&self, owner: String, reciver: String) -> Result<String> {
+        let reciver = self.get_user_id(reciver).await?;
+        self.get_chat(vec![owner, reciver]).await
+    }
+    async fn create_group(
+        &self,
+        owner: String,
+        title: String,
+        topic: Option<String>,
+        avatar: Option<String>,
+        members: Vec<String>,
+    ) -> Result<(String, Message)> {
+        let owner = user_thing(&owner)?;
+        let mut users = vec![owner.clone()];
+        for member in members.iter() {
+            let member = user_thing(member)?;
+            if !self.exsists(&member).await? {
+                return Err(ApiError::NotFound("user"));
+            }
+            if !users.contains(&member) {
+                users.push(member);
+            }
+        }
+        let chat = Chat {
+            kind: ChatKind::Group,
+            title: Some(title.clone()),
+            topic,
+            avatar,
+            roles: BTreeMap::from([(owner.to_string(), Role::Owner)]),
+            ..Chat::default()
+        };
+        let chat = self.create_chat(None, chat).await?;
+        self.chat_init(users, &chat).await?;
+        let name = self.username_of(&owner).await?;
+        let msg = Message::event(
+            ChatEvent::Created,
+            format!("{name} created {title}"),
+            owner.to_string(),
+        );
+        let chat = self.insert_to_chat(chat.to_string(), msg.clone()).await?;
+        Ok((chat, msg))
+    }
+    async fn update_group(
+        &self,
+        chat_id: String,
+        user: String,
+        title: Option<String>,
+        topic: Option<String>,
+        avatar: Option<String>,
+    ) -> Result<Message> {
+        let (chat, user) = (chat_thing(&chat_id)?, user_thing(&user)?);
+        self.group(&chat, &user, Permission::Rename).await?;
+        let mut changes = serde_json::Map::new();
+        if let Some(v) = &title {
+            changes.insert("title".into(), json!(v));
+        }
+        // null removes them
+        for (field, value) in [("topic", topic), ("avatar", avatar)] {
+            if let Some(v) = value {
+                changes.insert(field.into(), json!((!v.is_empty()).then_some(v)));
+            }
+        }
+        if changes.is_empty() {
+            return Err(ApiError::BadRequest("nothing to change".into()));
+        }
+        self.con
+            .query("UPDATE $chat MERGE $changes")
+            .bind(("chat", chat.clone()))
+            .bind(("changes", changes))
+            .await?
+            .check()?;
+        let name = self.username_of(&user).await?;
+        let text = match title {
+            Some(title) => format!("{name} renamed the group to {title}"),
+            None => format!("{name} changed the group"),
+        };
+        let msg = Message::event(ChatEvent::Updated, text, user.to_string());
+        self.insert_to_chat(chat_id, msg.clone()).await?;
+        Ok(msg)
+    }
+    async fn add_member(&self, chat_id: String, user: String, member: String) -> Result<Message> {
+        let (chat, user) = (chat_thing(&chat_id)?, user_thing(&user)?);
+        let member = user_thing(&member)?;
+        let membership = self.group(&chat, &user, Permission::Invite).await?;
+        if !self.exsists(&member).await? {
+            return Err(ApiError::NotFound("user"));
+        }
+        if membership.members.contains(&member) {
+            return Err(ApiError::BadRequest("already a member of this chat".into()));
+        }
+        self.chat_init(vec![member.clone()], &chat).await?;
+        let text = format!(
+            "{} added {}",
+            self.username_of(&user).await?,
+            self.username_of(&member).await?
+        );
+        let event = ChatEvent::Added {
+            user: member.to_string(),
+        };
+        let msg = Message::event(event, text, user.to_string());
+        self.insert_to_chat(chat_id, msg.clone()).await?;
+        Ok(msg)
+    }
+    async fn remove_member(
+        &self,
+        chat_id: String,
+        user: String,
+        member: String,
+    ) -> Result<Message> {
+        let (chat, user) = (chat_thing(&chat_id)?, user_thing(&user)?);
+        let member = user_thing(&member)?;
+        if member == user {
+            return self.leave_group(chat_id, user.to_string()).await;
+        }
+        let membership = self.group(&chat, &user, Permission::Kick).await?;
+        if !membership.members.contains(&member) {
+            return Err(ApiError::NotFound("member"));
+        }
+        // admins can't remove each other, only the owner can
+        if membership.role(&user) <= membership.role(&member) {
+            return Err(ApiError::Forbidden(Permission::Kick));
+        }
+        let mut roles = membership.roles;
+        roles.remove(&member.to_string());
+        self.detach(&chat, &member, roles).await?;
+        let text = format!(
+            "{} removed {}",
+            self.username_of(&user).await?,
+            self.username_of(&member).await?
+        );
+        let event = ChatEvent::Removed {
+            user: member.to_string(),
+        };
+        let msg = Message::event(event, text, user.to_string());
+        self.insert_to_chat(chat_id, msg.clone()).await?;
+        Ok(msg)
+    }
+    async fn leave_group(&self, chat_id: String, user: String) -> Result<Message> {
+        let (chat, user) = (chat_thing(&chat_id)?, user_thing(&user)?);
+        let membership = self.membership(&chat, &user).await?;
+        if membership.kind != ChatKind::Group {
+            return Err(ApiError::BadRequest("direct chats can't be left".into()));
+        }
+        let name = self.username_of(&user).await?;
+        let mut text = format!("{name} left");
+        let (roles, heir) = membership.succession(&user);
+        if let Some(heir) = heir {
+            text = format!(
+                "{text}, {} owns the group now",
+                self.username_of(heir).await?
+            );
+        }
+        self.detach(&chat, &user, roles).await?;
+        let msg = Message::event(ChatEvent::Left, text, user.to_string());
+        if membership.members.len() == 1 {
+            self.con.query("DELETE $chat").bind(("chat", chat)).await?;
+            log::info!("group {chat_id} was deleted, its last member left");
+        } else {
+            self.insert_to_chat(chat_id, msg.clone()).await?;
+        }
+        Ok(msg)
+    }
+    async fn set_role(
+        &self,
+        chat_id: String,
+        user: String,
+        member: String,
+        role: Role,
+    ) -> Result<Message> {
+        let (chat, user) = (chat_thing(&chat_id)?, user_thing(&user)?);
+        let member = user_thing(&member)?;
+        let membership = self.group(&chat, &user, Permission::ManageRoles).await?;
+        if !membership.members.contains(&member) {
+            return Err(ApiError::NotFound("member"));
+        }
+        if member == user {
+            return Err(ApiError::BadRequest(
+                "you can't change your own role".into(),
+            ));
+        }
+        let mut roles = membership.roles;
+        // there is only one owner, handing the group over makes the old one an admin
+        if role == Role::Owner {
+            roles.insert(user.to_string(), Role::Admin);
+        }
+        roles.insert(member.to_string(), role);
+        self.con
+            .query("UPDATE $chat SET roles = $roles")
+            .bind(("chat", chat))
+            .bind(("roles", roles))
+            .await?
+            .check()?;
+        let text = format!(
+            "{} made {} {}",
+            self.username_of(&user).await?,
+            self.username_of(&member).await?,
+            match role {
+                Role::Owner => "the owner",
+                Role::Admin => "an admin",
+                Role::Member => "a member",
+                Role::ReadOnly => "read only",
+            }
+        );
+        let event = ChatEvent::RoleChanged {
+            user: member.to_string(),
+            role,
+        };
+        let msg = Message::event(event, text, user.to_string());
+        self.insert_to_chat(chat_id, msg.clone()).await?;
+        Ok(msg)
+    }
+    async fn pin_message(
+        &self,
+        chat_id: String,
+        user: String,
+        message: String,
+        pin: bool,
+    ) -> Result<Message> {
+        let (chat, user) = (chat_thing(&chat_id)?, user_thing(&user)?);
+        self.authorize(&chat, &user, Permission::Pin).await?;
+        let mut result = self
+            .con
+            .query("SELECT pinned, messages.id AS messages FROM $chat")
+            .bind(("chat", chat.clone()))
+            .await?;
+        let pinned: Option<Vec<String>> = result.take((0, "pinned"))?;
+        let messages: Option<Vec<String>> = result.take((0, "messages"))?;
+        let mut pinned = pinned.unwrap_or_default();
+        if !messages.unwrap_or_default().contains(&message) {
+            return Err(ApiError::NotFound("message"));
+        }
+        let name = self.username_of(&user).await?;
+        let (event, text) = if pin {
+            if pinned.contains(&message) {
+                return Err(ApiError::BadRequest("the message is pinned already".into()));
+            }
+            pinned.push(message.clone());
+            (
+                ChatEvent::Pinned { message },
+                format!("{name} pinned a message"),
+            )
+        } else {
+            let Some(position) = pinned.iter().position(|m| *m == message) else {
+                return Err(ApiError::NotFound("pinned message"));
+            };
+            pinned.remove(position);
+            (
+                ChatEvent::Unpinned { message },
+                format!("{name} unpinned a message"),
+            )
+        };
+        self.con
+            .query("UPDATE $chat SET pinned = $pinned")
+            .bind(("chat", chat))
+            .bind(("pinned", pinned))
+            .await?
+            .check()?;
+        let msg = Message::event(event, text, user.to_string());
+        self.insert_to_chat(chat_id, msg.clone()).await?;
+        Ok(msg)
+    }
+    async fn delete_message(&self, chat_id: String, user: String, message: String) -> Result<()> {
+        let (chat, user) = (chat_thing(&chat_id)?, user_thing(&user)?);
+        let membership = self.membership(&chat, &user).await?;
+        let mut result = self
+            .con
+            .query("SELECT messages FROM $chat")
+            .bind(("chat", chat.clone()))
+            .await?;
+        let messages: Option<Vec<Message>> = result.take((0, "messages"))?;
+        let mut messages = messages.unwrap_or_default();
+        let Some(msg) = messages.iter_mut().find(|m| m.id == message) else {
+            return Err(ApiError::NotFound("message"));
+        };
+        if msg.owner != user.to_string() {
+            membership.allow(&user, Permission::DeleteOthers)?;
+        }
+        msg.text.clear();
+        msg.deleted = true;
+        self.con
+            .query("UPDATE $chat SET messages = $messages, pinned -= $message")
+            .bind(("chat", chat))
+            .bind(("messages", messages))
+            .bind(("message", message))
+            .await?
+            .check()?;
+        Ok(())
+    }
+    async fn check_permission(
+        &self,
+        chat_id: String,
+        user: String,
+        permission: Permission,
+    ) -> Result<()> {
+        let (chat, user) = (chat_thing(&chat_id)?, user_thing(&user)?);
+        self.authorize(&chat, &user, permission).await?;
+        Ok(())
+    }
+    async fn get_messages(
+        &self,
+        chat_id: String,
+        user: String,
+        before: Option<String>,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<String> {
+        let chat = chat_thing(&chat_id)?;
+        let mut result = self
+            .con
+            .query("SELECT * FROM $chat")
+            .bind(("chat", chat.clone()))
+            .await?;
+        let record: Option<ChatRecord> = result.take(0)?;
+        let record = match record {
+            Some(v) => v,
+            None => return Err(ApiError::NotFound("chat")),
+        };
+        let members: Vec<String> = record.members.iter().map(|m| m.to_string()).collect();
+        if !members.contains(&user) {
+            return Err(ApiError::NotMember);
+        }
+        let limit = limit.unwrap_or(HISTORY_LIMIT).clamp(1, HISTORY_LIMIT_MAX);
+        let (messages, more) = page(record.messages, before, after, limit)?;
+        Ok(json!({
+            "id" : chat.to_string(),
+            "kind" : record.kind,
+            "title" : record.title,
+            "topic" : record.topic,
+            "avatar" : record.avatar,
+            "members" : members,
+            "roles" : record.roles,
+            "pinned" : record.pinned,
+            "messages" : messages,
+            "more" : more
+        })
+        .to_string())
+    }
+    async fn get_members(&self, chat_id: String) -> Result<Vec<String>> {
+        let mut result = self
+            .con
+            .query("SELECT members FROM $chat")
+            .bind(("chat", chat_thing(&chat_id)?))
+            .await?;
+        let members: Option<Vec<Thing>> = result.take((0, "members"))?;
+        match members {
+            Some(v) => Ok(v.iter().map(|m| m.to_string()).collect()),
+            None => Err(ApiError::NotFound("chat")),
+        }
+    }
+    async fn get_rooms(&self, user: String) -> Result<Vec<(String, Vec<String>)>> {
+        let mut result = self
+            .con
+            .query("SELECT id, members FROM chat WHERE members CONTAINS $user")
+            .bind(("user", user_thing(&user)?))
+            .await?;
+        let rooms: Vec<Room> = result.take(0)?;
+        Ok(rooms
+            .into_iter()
+            .map(|r| {
+                let members = r.members.iter().map(|m| m.to_string()).collect();
+                (r.id.to_string(), members)
+            })
+            .collect())
+    }
+    async fn get_username(&self, sid: String) -> Result<String> {
+        let user = self.resolve(sid).await?;
+        let mut result = self
+            .con
+            .query("SELECT username FROM $user")
+            .bind(("user", user))
+            .await?;
+        let r: Option<String> = result.take((0, "username"))?;
+        match r {
+            Some(v) => Ok(v),
+            None => Err(ApiError::NotFound("account")),
+        }
+    }
+    async fn insert_to_chat(&self, chat_id: String, msg: Message) -> Result<String> {
+        let chat = chat_thing(&chat_id)?;
+        let mut _insertion = self
+            .con
+            .query("update $chat_id set messages += $msg")
+            .bind(("chat_id", chat.clone()))
+            .bind(("msg", msg))
+            .await?;
+        Ok(chat.to_string())
+    }
+    async fn get_id(&self, sid: String) -> Result<String> {
+        Ok(self.resolve(sid).await?.to_string())
+    }
+    async fn list_sessions(&self, sid: String) -> Result<String> {
+        let user = self.resolve(sid.clone()).await?;
+        let mut result = self
+            .con
+            .query("SELECT * FROM session WHERE user = $user ORDER BY last_seen DESC")
+            .bind(("user", user.to_string()))
+            .await?;
+        let sessions: Vec<SessionRecord> = result.take(0)?;
+        let mut active = vec![];
+        for record in sessions {
+            if self.expired(&record.session) {
+                self.delete_session(&record.id).await?;
+                continue;
+            }
+            active.push(json!({
+                "id" : record.id.to_string(),
+                "created" : record.session.created,
+                "last_seen" : record.session.last_seen,
+                "user_agent" : record.session.user_agent,
+                "ip" : record.session.ip,
+                "current" : record.session.token == token::hash(&sid)
+            }));
+        }
+        Ok(json!({ "sessions": active }).to_string())
+    }
+    async fn revoke_session(&self, sid: String, id: String) -> Result<()> {
+        let user = self.resolve(sid).await?;
+        let mut result = self
+            .con
+            .query("DELETE session WHERE id = $id AND user = $user RETURN BEFORE")
+            .bind(("id", string_into_thing(&id)?))
+            .bind(("user", user.to_string()))
+            .await?;
+        let deleted: Vec<SessionRecord> = result.take(0)?;
+        if deleted.is_empty() {
+            return Err(ApiError::NotFound("session"));
+        }
+        Ok(())
+    }
+    async fn revoke_sessions(&self, sid: String) -> Result<Vec<String>> {
+        let user = self.resolve(sid).await?;
+        let mut result = self
+            .con
+            .query("DELETE session WHERE user = $user RETURN BEFORE")
+            .bind(("user", user.to_string()))
+            .await?;
+        let deleted: Vec<SessionRecord> = result.take(0)?;
+        Ok(deleted.iter().map(|r| r.id.to_string()).collect())
+    }
+    async fn get_session(&self, sid: String) -> Result<String> {
+        Ok(self.find_session(sid).await?.id.to_string())
+    }
+    async fn logout(&self, sid: String) -> Result<String> {
+        let mut result = self
+            .con
+            .query("DELETE session WHERE token = $hash RETURN BEFORE")
+            .bind(("hash", token::hash(&sid)))
+            .await?;
+        let deleted: Option<SessionRecord> = result.take(0)?;
+        match deleted {
+            Some(v) => Ok(v.id.to_string()),
+            None => Err(ApiError::Unauthorized),
+        }
+    }
+}
+
+impl<C: Connection> SurrealDatabase<C> {
+    fn with(con: Surreal<C>, config: &config::Config) -> anyhow::Result<SurrealDatabase<C>> {
+        let secrets = cryption::Secrets::load(&config.two_factor)?;
+        Ok(SurrealDatabase {
+            con,
+            idle: Duration::hours(config.session.idle_hours as i64),
+            lifetime: Duration::hours(config.session.lifetime_hours as i64),
+            verify: Duration::hours(config.mail.verify_hours as i64),
+            reset: Duration::minutes(config.mail.reset_minutes as i64),
+            pending: Duration::minutes(config.two_factor.pending_minutes as i64),
+            secrets,
+            password: config.password.clone(),
+            retention: config.account.deleted_messages,
+        })
+    }
+    /// Indexes emails and usernames lowercased, so they stay unique even when
+    /// two signups race past the checks. Accounts from before get their keys
+    /// filled in first.
+    async fn define_unique(&self) -> anyhow::Result<()> {
+        self.con
+            .query("DEFINE FIELD email_key ON user VALUE string::lowercase(email)")
+            .query("DEFINE FIELD username_key ON user VALUE string::lowercase(username)")
+            .query("UPDATE user WHERE email_key = NONE OR username_key = NONE")
+            .await?
+            .check()?;
+        for (index, field) in [(EMAIL_INDEX, "email_key"), (USERNAME_INDEX, "username_key")] {
+            let defined = self
+                .con
+                .query(format!(
+                    "DEFINE INDEX {index} ON user FIELDS {field} UNIQUE"
+                ))
+                .await?
+                .check();
+            // older accounts may share one ignoring case, they have to be told apart by hand
+            if let Err(err) = defined {
+                log::warn!("{field} of accounts isn't unique, not indexing it : {err}");
+            }
+        }
+        Ok(())
+    }
+    /// Stores the hash of a new single use token for `user` and returns the token.
+    async fn create_token(
+        &self,
+        kind: token::Kind,
+        user: &Thing,
+        email: Option<String>,
+    ) -> Result<String> {
+        let plain = token::generate(kind);
+        let _created: Record = self
+            .con
+            .create("token")
+            .content(Token {
+                token: token::hash(&plain),
+                kind,
+                user: user.to_string(),
+                created: Utc::now().to_rfc3339(),
+                email,
+            })
+            .await?;
+        Ok(plain)
+    }
+    /// Uses up a single use token and returns it, if it is younger than `valid`.
+    async fn take_token(&self, kind: token::Kind, plain: &str, valid: Duration) -> Result<Token> {
+        if token::Kind::of(plain) != Some(kind) {
+            return Err(ApiError::InvalidToken);
+        }
+        let mut result = self
+            .con
+            .query("DELETE token WHERE token = $hash AND kind = $kind RETURN BEFORE")
+            .bind(("hash", token::hash(plain)))
+            .bind(("kind", kind))
+            .await?;
+        let deleted: Option<Token> = result.take(0)?;
+        let Some(record) = deleted else {
+            return Err(ApiError::InvalidToken);
+        };
+        match DateTime::parse_from_rfc3339(&record.created) {
+            Ok(created) if Utc::now() - created.with_timezone(&Utc) <= valid => Ok(record),
+            _ => Err(ApiError::InvalidToken),
+        }
+    }
+    async fn check_password(&self, user: &Thing, password: &str) -> Result<()> {
+        let mut result = self
+            .con
+            .query("SELECT passhash FROM $user")
+            .bind(("user", user.clone()))
+            .await?;
+        let passhash: Option<String> = result.take((0, "passhash"))?;
+        match passhash {
+            Some(v) if cryption::verify(password.as_bytes(), v.clone()).await? => Ok(()),
+            _ => Err(ApiError::InvalidCredentials),
+        }
+    }
+    /// Tells whether an account other than `except` uses the email, ignoring case.
+    async fn email_taken(&self, email: &str, except: Option<&Thing>) -> Result<bool> {
+        let mut result = self
+            .con
+            .query("SELECT id FROM user WHERE string::lowercase(email) = $email")
+            .bind(("email", email.to_lowercase()))
+            .await?;
+        let users: Vec<Record> = result.take(0)?;
+        Ok(users.iter().any(|u| Some(&u.id) != except))
+    }
+    /// Accepts a TOTP code of the account's secret or one of its unused
+    /// recovery codes, which is used up.
+    async fn check_code(&self, user: &Thing, code: &str) -> Result<()> {
+        let mut result = self
+            .con
+            .query("SELECT totp, totp_step, recovery_codes FROM $user")
+            .bind(("user", user.clone()))
+            .await?;
+        let sealed: Option<String> = result.take((0, "totp"))?;
+        let Some(sealed) = sealed else {
+            return Err(ApiError::BadRequest(
+                "two factor authentication is off".into(),
+            ));
+        };
+        let last: Option<u64> = result.take((0, "totp_step"))?;
+        let secret = self.secrets.decrypt(&sealed, &user.to_string())?;
+        if let Some(step) = totp::check(&secret, code, last) {
+            self.con
+                .query("UPDATE $user SET totp_step = $step")
+                .bind(("user", user.clone()))
+                .bind(("step", step))
+                .await?;
+            return Ok(());
+        }
+        let codes: Option<Vec<String>> = result.take((0, "recovery_codes"))?;
+        let hash = token::hash(&totp::normalize(code));
+        if !codes.unwrap_or_default().contains(&hash) {
+            return Err(ApiError::InvalidCode);
+        }
+        self.con
+            .query("UPDATE $user SET recovery_codes -= $hash")
+            .bind(("user", user.clone()))
+            .bind(("hash", hash))
+            .await?;
+        log::info!("{user} logged in with a recovery code");
+        Ok(())
+    }
+    async fn set_password(&self, user: &Thing, password: &str) -> Result<()> {
+        self.con
+            .query("UPDATE $user SET passhash = $passhash")
+            .bind(("user", user.clone()))
+            .bind((
+                "passhash",
+                cryption::new(password.as_bytes(), &self.password).await?,
+            ))
+            .await?;
+        Ok(())
+    }
+    async fn create_session(&self, user: String, user_agent: String, ip: String) -> Result<String> {
+        let sid = token::generate(token::Kind::Session);
+        let now = Utc::now().to_rfc3339();
+        let _created: Record = self
+            .con
+            .create("session")
+            .content(Session {
+                token: token::hash(&sid),
+                user,
+                created: now.clone(),
+                last_seen: now,
+                user_agent,
+                ip,
+            })
+            .await?;
+        Ok(sid)
+    }
+    /// Returns the user a session belongs to, expired sessions are removed on the way.
+    async fn resolve(&self, sid: String) -> Result<Thing> {
+        string_into_thing(&self.find_session(sid).await?.session.user)
+    }
+    async fn find_session(&self, sid: String) -> Result<SessionRecord> {
+        if token::Kind::of(&sid) != Some(token::Kind::Session) {
+            return Err(ApiError::Unauthorized);
+        }
+        let mut result = self
+            .con
+            .query("SELECT * FROM session WHERE token = $hash")
+            .bind(("hash", token::hash(&sid)))
+            .await?;
+        let record: Option<SessionRecord> = result.take(0)?;
+        let record = match record {
+            Some(v) => v,
+            None => return Err(ApiError::Unauthorized),
+        };
+        if self.expired(&record.session) {
+            self.delete_session(&record.id).await?;
+            return Err(ApiError::SessionExpired);
+        }
+        self.con
+            .query("UPDATE $id SET last_seen = $now")
+            .bind(("id", record.id.clone()))
+            .bind(("now", Utc::now().to_rfc3339()))
+            .await?;
+        Ok(record)
+    }
+    fn expired(&self, session: &Session) -> bool {
+        let parse = |v: &str| DateTime::parse_from_rfc3339(v).map(|d| d.with_timezone(&Utc));
+        match (parse(&session.created), parse(&session.last_seen)) {
+            (Ok(created), Ok(last_seen)) => {
+                let now = Utc::now();
+                now - created > self.lifetime || now - last_seen > self.idle
+            }
+            _ => true,
+        }
+    }
+    async fn delete_session(&self, id: &Thing) -> Result<()> {
+        self.con
+            .query("DELETE $id")
+            .bind(("id", id.clone()))
+            .await?;
+        Ok(())
+    }
+    /// Usernames are unique ignoring case, so they are looked up that way too.
+    async fn get_user_id(&self, username: String) -> Result<String> {
+        let mut result = self
+            .con
+            .query("SELECT id FROM user WHERE string::lowercase(username) = $username")
+            .bind(("username", username.trim().to_lowercase()))
+            .await?;
+        let r: Option<Thing> = result.take((0, "id"))?;
+        match r {
+            Some(v) => Ok(format!("{v}")),
+            None => Err(ApiError::NotFound("user")),
+        }
+    }
+
+    // adding someone twice changes nothing
+    async fn chat_init(&self, users: Vec<Thing>, chat: &Thing) -> Result<()> {
+        for id in users.iter() {
+            self.con
+                .query("UPDATE $chat SET members = array::union(members, [$id])")
+                .query("UPDATE $id SET chats = array::union(chats, [$chat])")
+                .bind(("chat", chat.clone()))
+                .bind(("id", id.clone()))
+                .await?
+                .check()?;
+        }
+        Ok(())
+    }
+    /// Creates a chat without members, with the given id or a random one.
+    async fn create_chat(&self, id: Option<&Thing>, chat: Chat) -> Result<Thing> {
+        let Some(id) = id else {
+            let created: Record = self.con.create("chat").content(chat).await?;
+            return Ok(created.id);
+        };
+        self.con
+            .query("CREATE $chat CONTENT $content")
+            .bind(("chat", id.clone()))
+            .bind(("content", chat))
+            .await?
+            .check()?;
+        Ok(id.clone())
+    }
+    async fn chat_exists(&self, chat: &Thing) -> Result<bool> {
+        let mut result = self
+            .con
+            .query("SELECT id FROM $chat")
+            .bind(("chat", chat))
+            .await?;
+        let found: Option<Thing> = result.take((0, "id"))?;
+        Ok(found.is_some())
+    }
+    /// Loads the members of a chat `user` is a member of.
+    async fn membership(&self, chat: &Thing, user: &Thing) -> Result<Membership> {
+        let mut result = self
+            .con
+            .query("SELECT kind, members, roles FROM $chat")
+            .bind(("chat", chat))
+            .await?;
+        let membership: Option<Membership> = result.take(0)?;
+        let Some(membership) = membership else {
+            return Err(ApiError::NotFound("chat"));
+        };
+        if !membership.members.contains(user) {
+            return Err(ApiError::NotMember);
+        }
+        Ok(membership)
+    }
+    /// Like `membership`, and checks `user` may do `permission` in the chat.
+    async fn authorize(
+        &self,
+        chat: &Thing,
+        user: &Thing,
+        permission: Permission,
+    ) -> Result<Membership> {
+        let membership = self.membership(chat, user).await?;
+        membership.allow(user, permission)?;
+        if membership.kind == ChatKind::Direct && permission == Permission::Send {
+            self.direct_allowed(&membership.members).await?;
+        }
+        Ok(membership)
+    }
+    /// Like `authorize`, for changes only groups allow.
+    async fn group(
+        &self,
+        chat: &Thing,
+        user: &Thing,
+        permission: Permission,
+    ) -> Result<Membership> {
+        let membership = self.membership(chat, user).await?;
+        if membership.kind != ChatKind::Group {
+            return Err(ApiError::BadRequest("direct chats can't be changed".into()));
+        }
+        membership.allow(user, permission)?;
+        Ok(membership)
+    }
+    /// Takes `user` out of the chat, `roles` are what is left of them.
+    async fn detach(
+        &self,
+        chat: &Thing,
+        user: &Thing,
+        roles: BTreeMap<String, Role>,
+    ) -> Result<()> {
+        self.con
+            .query("UPDATE $chat SET members -= $user, roles = $roles")
+            .query("UPDATE $user SET chats -= $chat")
+            .query("UPDATE $user SET muted -= $chat WHERE muted CONTAINS $chat")
+            .bind(("chat", chat))
+            .bind(("user", user))
+            .bind(("roles", roles))
+            .await?
+            .check()?;
+        Ok(())
+    }
+    /// Fails for two users who aren't contacts when one of them only takes
+    /// direct messages from contacts.
+    async fn direct_allowed(&self, members: &[Thing]) -> Result<()> {
+        let [a, b] = members else {
+            return Ok(());
+        };
+        if self.blocked(a, b).await? {
+            return Err(ApiError::Blocked);
+        }
+        let mut result = self
+            .con
+            .query("SELECT id, contacts_only, contacts FROM $a, $b")
+            .bind(("a", a))
+            .bind(("b", b))
+            .await?;
+        let accounts: Vec<Privacy> = result.take(0)?;
+        let restricted = accounts.iter().any(|v| v.contacts_only);
+        let contacts = accounts
+            .iter()
+            .any(|v| v.id == *a && v.contacts.contains(b));
+        if restricted && !contacts {
+            return Err(ApiError::ContactsOnly);
+        }
+        Ok(())
+    }
+    /// Whether one of the two users blocked the other.
+    async fn blocked(&self, a: &Thing, b: &Thing) -> Result<bool> {
+        let mut result = self
+            .con
+            .query("SELECT id FROM $a, $b WHERE blocked CONTAINS $a OR blocked CONTAINS $b")
+            .bind(("a", a))
+            .bind(("b", b))
+            .await?;
+        let found: Vec<Record> = result.take(0)?;
+        Ok(!found.is_empty())
+    }
+    async fn filters(&self, user: &Thing) -> Result<Filters> {
+        let mut result = self
+            .con
+            .query("SELECT blocked, muted FROM $user")
+            .bind(("user", user))
+            .await?;
+        let filters: Option<Filters> = result.take(0)?;
+        Ok(filters.unwrap_or_default())
+    }
+    async fn are_contacts(&self, user: &Thing, other: &Thing) -> Result<bool> {
+        let mut result = self
+            .con
+            .query("SELECT id FROM $user WHERE contacts CONTAINS $other")
+            .bind(("user", user))
+            .bind(("other", other))
+            .await?;
+        let found: Option<Thing> = result.take((0, "id"))?;
+        Ok(found.is_some())
+    }
+    /// Deletes the request `sender` sent to `recipient`, if there is one.
+    async fn take_request(&self, sender: &Thing, recipient: &Thing) -> Result<bool> {
+        let mut result = self
+            .con
+            .query("DELETE contact_request WHERE sender = $sender AND recipient = $recipient RETURN BEFORE")
+            .bind(("sender", sender.to_string()))
+            .bind(("recipient", recipient.to_string()))
+            .await?;
+        let deleted: Vec<Record> = result.take(0)?;
+        Ok(!deleted.is_empty())
+    }
+    async fn connect(&self, user: &Thing, other: &Thing) -> Result<()> {
+        // accounts from before contacts existed don't have the field
+        self.con
+            .query("UPDATE $user SET contacts = array::union(contacts OR [], [$other])")
+            .query("UPDATE $other SET contacts = array::union(contacts OR [], [$user])")
+            .bind(("user", user))
+            .bind(("other", other))
+            .await?
+            .check()?;
+        Ok(())
+    }
+    async fn contacts_of(&self, user: &Thing) -> Result<Vec<serde_json::Value>> {
+        let mut result = self
+            .con
+            .query("SELECT contacts FROM $user")
+            .bind(("user", user))
+            .await?;
+        let contacts: Option<Vec<Thing>> = result.take((0, "contacts"))?;
+        let contacts = contacts.unwrap_or_default();
+        if contacts.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut result = self
+            .con
+            .query("SELECT id, username, public_key FROM $contacts")
+            .bind(("contacts", contacts))
+            .await?;
+        let records: Vec<ContactRecord> = result.take(0)?;
+        Ok(records
+            .into_iter()
+            .map(|r| {
+                json!({
+                    "id" : r.id.to_string(),
+                    "username" : r.username,
+                    "public_key" : r.public_key
+                })
+            })
+            .collect())
+    }
+    /// Adds the usernames to the other users and creation dates of requests.
+    async fn describe_requests(
+        &self,
+        requests: Vec<(String, String)>,
+    ) -> Result<Vec<serde_json::Value>> {
+        let mut described = vec![];
+        for (id, created) in requests {
+            // the other account may be gone
+            let Ok(username) = self.username_of(&string_into_thing(&id)?).await else {
+                continue;
+            };
+            described.push(json!({
+                "id" : id,
+                "username" : username,
+                "created" : created
+            }));
+        }
+        Ok(described)
+    }
+    async fn username_of(&self, user: &Thing) -> Result<String> {
+        let mut result = self
+            .con
+            .query("SELECT username FROM $user")
+            .bind(("user", user))
+            .await?;
+        let username: Option<String> = result.take((0, "username"))?;
+        username.ok_or(ApiError::NotFound("user"))
+    }
+    async fn exsists(&self, id: &Thing) -> Result<bool> {
+        let mut result = self
+            .con
+            .query("select id from user where id = $id")
+            .bind(("id", id))
+            .await?;
+        let user: Option<Thing> = result.take("id")?;
+        Ok(user.is_some())
+    }
+}
+/// The one chat between two users, or of a user with themself, has an id made
+/// of their sorted ids so it can't be created twice.
+fn direct_key(members: &[Thing]) -> Thing {
+    let ids: Vec<String> = members.iter().map(|v| v.id.to_raw()).collect();
+    Thing::from(("chat", format!("dm_{}", ids.join("_")).as_str()))
+}
+fn string_into_thing(s: &str) -> Result<Thing> {
+    match s.split_once(':') {
+        Some(r) => Ok(Thing::from(r)),
+        None => Err(ApiError::BadRequest(format!("{s} isn't a record id"))),
+    }
+}
+// Chat ids are accepted with or without their `chat:` table prefix.
+fn chat_thing(chat_id: &str) -> Result<Thing> {
+    match chat_id.split_once(':') {
+        Some(("chat", _)) => string_into_thing(chat_id),
+        Some(_) => Err(ApiError::NotFound("chat")),
+        None => Ok(Thing::from(("chat", chat_id))),
+    }
+}
+// User ids are too, `get_data` hands them out without it.
+fn user_thing(user_id: &str) -> Result<Thing> {
+    match user_id.split_once(':') {
+        Some(("user", _)) => string_into_thing(user_id),
+        Some(_) => Err(ApiError::NotFound("user")),
+        None => Ok(Thing::from(("user", user_id))),
+    }
+}
+
+// Messages are stored oldest first. Without a cursor the newest `limit` messages are
+// returned, `after` pages forward from a message and `before` pages backwards.
+/// Turns a write that broke the email or username index into the error the
+/// checks before it would have given.
+fn unique(err: surrealdb::Error) -> ApiError {
+    let message = err.to_string();
+    if message.contains(&format!("index `{EMAIL_INDEX}`")) {
+        return ApiError::AccountExists;
+    }
+    if message.contains(&format!("index `{USERNAME_INDEX}`")) {
+        return ApiError::UsernameTaken;
+    }
+    err.into()
+}
+
+fn page(
+    mut messages: Vec<Message>,
+    before: Option<String>,
+    after: Option<String>,
+    limit: usize,
+) -> Result<(Vec<Message>, bool)> {
+    let position = |messages: &Vec<Message>, id: &String| {
+        messages
+            .iter()
+            .position(|m| &m.id == id)
+            .ok_or(ApiError::NotFound("message"))
+    };
+    if let Some(id) = before {
+        messages.truncate(position(&messages, &id)?);
+    }
+    if let Some(id) = after {
+        let start = position(&messages, &id)? + 1;
+        messages.drain(..start);
+        let more = messages.len() > limit;
+        messages.truncate(limit);
+        return Ok((messages, more));
+    }
+    let start = messages.len().saturating_sub(limit);
+    Ok((messages.split_off(start), start > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn memory() -> MemoryDatabase {
+        MemoryDatabase::memory(&config::Config::default())
+            .await
+            .unwrap()
+    }
+
+    // a verified account, its id and the id of a session of it
+    async fn account(db: &MemoryDatabase, name: &str) -> (String, String) {
+        let email = format!("{name}@example.com");
+        let token = db
+            .signup(email.clone(), name.into(), "correct horse".into())
+            .await
+            .unwrap();
+        db.verify_email(token).await.unwrap();
+        let login = db
+            .login(email, "correct horse".into(), "test".into(), "::1".into())
+            .await
+            .unwrap();
+        let Login::Session(sid) = login else {
+            panic!("no second factor was set up");
+        };
+        (db.get_id(sid.clone()).await.unwrap(), sid)
+    }
+
+    // what signup stores, without the checks before it
+    async fn create_user(db: &MemoryDatabase, email: &str, username: &str) -> Result<Record> {
+        let created: Record = db
+            .con
+            .create("user")
+            .content(serde_json::json!({ "email": email, "username": username }))
+            .await
+            .map_err(unique)?;
+        Ok(created)
+    }
+
+    #[actix_web::test]
+    async fn signup_ignores_case() {
+        let db = memory().await;
+        account(&db, "alice").await;
+        let signup = |email: &str, username: &str| {
+            db.signup(email.into(), username.into(), "correct horse".into())
+        };
+        assert!(matches!(
+            signup("ALICE@example.com", "alice2").await,
+            Err(ApiError::AccountExists)
+        ));
+        assert!(matches!(
+            signup("alice2@example.com", "Alice").await,
+            Err(ApiError::UsernameTaken)
+        ));
+        assert!(signup("alice2@example.com", "alice2").await.is_ok());
+    }
+
+    // two signups may both pass the checks, the indexes still keep one out
+    #[actix_web::test]
+    async fn unique_indexes() {
+        let db = memory().await;
+        create_user(&db, "alice@example.com", "alice")
+            .await
+            .unwrap();
+        assert!(matches!(
+            create_user(&db, "Alice@Example.com", "alice2").await,
+            Err(ApiError::AccountExists)
+        ));
+        assert!(matches!(
+            create_user(&db, "alice2@example.com", "ALICE").await,
+            Err(ApiError::UsernameTaken)
+        ));
+        create_user(&db, "alice2@example.com", "alice2")
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn unique_indexes_of_older_accounts() {
+        let db = memory().await;
+        db.con
+            .query("REMOVE INDEX user_email ON user")
+            .query("REMOVE INDEX user_username ON user")
+            .query("REMOVE FIELD email_key ON user")
+            .query("REMOVE FIELD username_key ON user")
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+        create_user(&db, "Alice@example.com", "alice")
+            .await
+            .unwrap();
+        create_user(&db, "bob@example.com", "Alice").await.unwrap();
+
+        // the usernames clash, only the emails get indexed
+        db.define_unique().await.unwrap();
+        assert!(matches!(
+            create_user(&db, "alice@EXAMPLE.com", "carol").await,
+            Err(ApiError::AccountExists)
+        ));
+        create_user(&db, "carol@example.com", "alice")
+            .await
+            .unwrap();
+    }
+
+    // ten messages with the ids 0 to 9, oldest first
+    fn history() -> Vec<Message> {
+        (0..10)
+            .map(|i| Message {
+                id: i.to_string(),
+                ..Message::new(format!("message {i}"), "user:alice".into())
+            })
+            .collect()
+    }
+
+    fn ids(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.id.as_str()).collect()
+    }
+
+    fn cursor(id: &str) -> Option<String> {
+        Some(id.into())
+    }
+
+    #[test]
+    fn page_without_cursor() {
+        let (messages, more) = page(history(), None, None, 3).unwrap();
+        assert_eq!(ids(&messages), ["7", "8", "9"]);
+        assert!(more);
+
+        let (messages, more) = page(history(), None, None, 10).unwrap();
+        assert_eq!(messages.len(), 10);
+        assert!(!more);
+    }
+
+    #[test]
+    fn page_before() {
+        let (messages, more) = page(history(), cursor("5"), None, 3).unwrap();
+        assert_eq!(ids(&messages), ["2", "3", "4"]);
+        assert!(more);
+
+        let (messages, more) = page(history(), cursor("2"), None, 3).unwrap();
+        assert_eq!(ids(&messages), ["0", "1"]);
+        assert!(!more);
+    }
+
+    #[test]
+    fn page_after() {
+        let (messages, more) = page(history(), None, cursor("5"), 3).unwrap();
+        assert_eq!(ids(&messages), ["6", "7", "8"]);
+        assert!(more);
+
+        let (messages, more) = page(history(), None, cursor("6"), 3).unwrap();
+        assert_eq!(ids(&messages), ["7", "8", "9"]);
+        assert!(!more);
+    }
+
+    #[test]
+    fn page_between() {
+        let (messages, more) = page(history(), cursor("7"), cursor("2"), 10).unwrap();
+        assert_eq!(ids(&messages), ["3", "4", "5", "6"]);
+        assert!(!more);
+
+        let (messages, more) = page(history(), cursor("7"), cursor("2"), 2).unwrap();
+        assert_eq!(ids(&messages), ["3", "4"]);
+        assert!(more);
+    }
+
+    #[test]
+    fn page_unknown_cursor() {
+        for (before, after) in [
+            (cursor("x"), None),
+            (None, cursor("x")),
+            (cursor("3"), cursor("x")),
+        ] {
+            assert!(matches!(
+                page(history(), before, after, 3),
+                Err(ApiError::NotFound("message"))
+            ));
+        }
+    }
+
+    #[actix_web::test]
+    async fn direct_chats_are_reused() {
+        let db = memory().await;
+        let (alice, _) = account(&db, "alice").await;
+        let (bob, bob_sid) = account(&db, "bob").await;
+
+        let chat = db.get_chat(vec![alice.clone(), bob.clone()]).await.unwrap();
+        assert_eq!(
+            db.get_chat(vec![bob.clone(), alice.clone()]).await.unwrap(),
+            chat
+        );
+        let bare = bob.trim_start_matches("user:").to_string();
+        assert_eq!(db.get_chat(vec![alice.clone(), bare]).await.unwrap(), chat);
+
+        // the chat with bob keeps only alice as its member once he is gone,
+        // it isn't mistaken for her chat with herself
+        db.delete_account(bob_sid, "correct horse".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_members(chat.clone()).await.unwrap(),
+            vec![alice.clone()]
+        );
+        let own = db.get_chat(vec![alice.clone()]).await.unwrap();
+        assert_ne!(own, chat);
+        assert_eq!(db.get_chat(vec![alice.clone(), alice]).await.unwrap(), own);
+    }
+
+    #[actix_web::test]
+    async fn legacy_direct_chats_are_found() {
+        let db = memory().await;
+        let (alice, _) = account(&db, "alice").await;
+        let (bob, _) = account(&db, "bob").await;
+        let members = vec![user_thing(&alice).unwrap(), user_thing(&bob).unwrap()];
+        let legacy = db.create_chat(None, Chat::default()).await.unwrap();
+        db.chat_init(members, &legacy).await.unwrap();
+        assert_eq!(
+            db.get_chat(vec![bob, alice.clone()]).await.unwrap(),
+            legacy.to_string()
+        );
+        assert_ne!(db.get_chat(vec![alice]).await.unwrap(), legacy.to_string());
+    }
+}
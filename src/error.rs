@@ -0,0 +1,200 @@
+use crate::table::Permission;
+use actix_web::{
+    http::{header, StatusCode},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use serde_json::json;
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+pub type Result<T> = std::result::Result<T, ApiError>;
+
+/// Everything a request can fail with. Clients get a json body with a stable
+/// `code` to match on and a human readable `error`, internal errors are logged
+/// and only reported as `internal`.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request is malformed, the message says what is wrong with it.
+    BadRequest(String),
+    /// Maps the fields of a form to what is wrong with them.
+    Invalid(BTreeMap<&'static str, String>),
+    /// There is no session, or it was ended.
+    Unauthorized,
+    SessionExpired,
+    InvalidCredentials,
+    EmailNotVerified,
+    /// The TOTP or recovery code is wrong.
+    InvalidCode,
+    TwoFactorEnabled,
+    /// A single use token that is unknown, used up or expired.
+    InvalidToken,
+    NotMember,
+    /// The role of the user in the chat doesn't allow this.
+    Forbidden(Permission),
+    /// The other user only takes direct messages from their contacts.
+    ContactsOnly,
+    /// One of the two users blocked the other.
+    Blocked,
+    /// Names what wasn't found, like `chat` or `user`.
+    NotFound(&'static str),
+    AccountExists,
+    UsernameTaken,
+    /// Too many failed logins, holds how long until the next attempt is let through.
+    TooManyAttempts(Duration),
+    Internal(anyhow::Error),
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Invalid(_) => "invalid_fields",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::SessionExpired => "session_expired",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::EmailNotVerified => "email_not_verified",
+            ApiError::InvalidCode => "invalid_code",
+            ApiError::TwoFactorEnabled => "two_factor_enabled",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::NotMember => "not_a_member",
+            ApiError::Forbidden(permission) => match permission {
+                Permission::Send => "cannot_send",
+                Permission::Invite => "cannot_invite",
+                Permission::Kick => "cannot_kick",
+                Permission::Rename => "cannot_rename",
+                Permission::Pin => "cannot_pin",
+                Permission::DeleteOthers => "cannot_delete",
+                Permission::ManageRoles => "cannot_manage_roles",
+            },
+            ApiError::ContactsOnly => "contacts_only",
+            ApiError::Blocked => "blocked",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::AccountExists => "account_exists",
+            ApiError::UsernameTaken => "username_taken",
+            ApiError::TooManyAttempts(_) => "too_many_attempts",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+    /// What the client gets to see.
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::BadRequest(v) => v.clone(),
+            ApiError::Invalid(_) => "some fields are invalid".into(),
+            ApiError::Unauthorized => "not logged in".into(),
+            ApiError::SessionExpired => "session expired".into(),
+            ApiError::InvalidCredentials => "wrong email or password".into(),
+            ApiError::EmailNotVerified => "verify your email before logging in".into(),
+            ApiError::InvalidCode => "wrong two factor code".into(),
+            ApiError::TwoFactorEnabled => "two factor authentication is already on".into(),
+            ApiError::InvalidToken => "this link is invalid or has expired".into(),
+            ApiError::NotMember => "not a member of this chat".into(),
+            ApiError::Forbidden(permission) => format!(
+                "your role in this chat doesn't allow you to {}",
+                match permission {
+                    Permission::Send => "send messages",
+                    Permission::Invite => "add members",
+                    Permission::Kick => "remove this member",
+                    Permission::Rename => "change the group",
+                    Permission::Pin => "pin messages",
+                    Permission::DeleteOthers => "delete messages of others",
+                    Permission::ManageRoles => "change roles",
+                }
+            ),
+            ApiError::ContactsOnly => "this user only accepts messages from contacts".into(),
+            ApiError::Blocked => "you can't reach this user".into(),
+            ApiError::NotFound(what) => format!("no such {what}"),
+            ApiError::AccountExists => "an account with this email already exists".into(),
+            ApiError::UsernameTaken => "this username is taken".into(),
+            ApiError::TooManyAttempts(wait) => format!(
+                "too many failed logins, try again in {} seconds",
+                retry_after(wait)
+            ),
+            ApiError::Internal(_) => "internal server error".into(),
+        }
+    }
+    /// Which form fields the error is about, for forms to show next to them.
+    pub fn fields(&self) -> BTreeMap<&'static str, String> {
+        match self {
+            ApiError::Invalid(fields) => fields.clone(),
+            ApiError::AccountExists => BTreeMap::from([("email", self.message())]),
+            ApiError::UsernameTaken => BTreeMap::from([("username", self.message())]),
+            _ => BTreeMap::new(),
+        }
+    }
+    pub fn log(&self) {
+        if let ApiError::Internal(err) = self {
+            log::error!("{err:#}");
+        }
+    }
+}
+
+// shows the details of internal errors, unlike `message`
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Internal(err) => write!(f, "{err:#}"),
+            _ => f.write_str(&self.message()),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::Invalid(_) | ApiError::InvalidToken => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::Unauthorized
+            | ApiError::SessionExpired
+            | ApiError::InvalidCredentials
+            | ApiError::InvalidCode => StatusCode::UNAUTHORIZED,
+            ApiError::NotMember
+            | ApiError::EmailNotVerified
+            | ApiError::Forbidden(_)
+            | ApiError::ContactsOnly
+            | ApiError::Blocked => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::AccountExists | ApiError::UsernameTaken | ApiError::TwoFactorEnabled => {
+                StatusCode::CONFLICT
+            }
+            ApiError::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        self.log();
+        let fields = self.fields();
+        let body = if fields.is_empty() {
+            json!({ "error" : self.message(), "code" : self.code() })
+        } else {
+            json!({ "error" : self.message(), "code" : self.code(), "fields" : fields })
+        };
+        let mut res = HttpResponse::build(self.status_code());
+        if let ApiError::TooManyAttempts(wait) = self {
+            res.insert_header((header::RETRY_AFTER, retry_after(wait)));
+        }
+        res.json(body)
+    }
+}
+
+/// Error handler for the json, query and path extractors, so a body or url
+/// that doesn't parse gets the same json error as everything else.
+pub fn bad_request(err: impl fmt::Display, _: &HttpRequest) -> actix_web::Error {
+    ApiError::BadRequest(err.to_string()).into()
+}
+
+// whole seconds, rounded up so clients don't retry a moment too early
+fn retry_after(wait: &Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> ApiError {
+        ApiError::Internal(err)
+    }
+}
+
+impl From<surrealdb::Error> for ApiError {
+    fn from(err: surrealdb::Error) -> ApiError {
+        ApiError::Internal(err.into())
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{
+    config,
+    error::{ApiError, Result},
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// failures of this many keys are kept before forgotten ones are cleaned up
+const PRUNE_AT: usize = 1024;
+
+/// Counts failed logins per client address and per account, shared by all
+/// workers. Keys past their limit are locked out, see [`config::Login`].
+pub struct Limiter {
+    config: config::Login,
+    failures: Mutex<HashMap<Key, Failures>>,
+}
+
+#[derive(Hash, PartialEq, Eq, Clone)]
+enum Key {
+    Ip(String),
+    /// Normalized email of the account.
+    Account(String),
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Limiter {
+    pub fn new(config: &config::Login) -> Limiter {
+        Limiter {
+            config: config.clone(),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Fails with `ApiError::TooManyAttempts` while the address or the account
+    /// is locked out, before the password is even looked at.
+    pub fn check(&self, ip: &str, account: Option<&str>) -> Result<()> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        let wait = keys(ip, account)
+            .iter()
+            .filter_map(|key| failures.get(key)?.locked_until)
+            .filter_map(|until| until.checked_duration_since(now))
+            .max();
+        match wait {
+            Some(wait) => Err(ApiError::TooManyAttempts(wait)),
+            None => Ok(()),
+        }
+    }
+    /// Counts a failed login from `ip`, and for `account` when it is known.
+    pub fn fail(&self, ip: &str, account: Option<&str>) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_minutes * 60);
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= PRUNE_AT {
+            failures.retain(|_, v| !v.expired(now, window));
+        }
+        for key in keys(ip, account) {
+            let entry = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            if entry.expired(now, window) {
+                entry.count = 0;
+            }
+            entry.count += 1;
+            entry.last = now;
+            let over = entry.count.saturating_sub(key.limit(&self.config));
+            if over > 0 {
+                let lockout = self.lockout(over);
+                entry.locked_until = Some(now + lockout);
+                log::warn!(
+                    "{key} locked out for {}s after {} failed logins, last one from {ip}",
+                    lockout.as_secs(),
+                    entry.count
+                );
+            }
+        }
+    }
+    /// Forgets the failures of an account once it logged in.
+    pub fn reset(&self, account: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&Key::Account(account.to_string()));
+    }
+    // doubles with every failure past the limit
+    fn lockout(&self, over: u32) -> Duration {
+        let max = self.config.max_lockout_minutes * 60;
+        let seconds = 2u64
+            .checked_pow(over - 1)
+            .and_then(|v| v.checked_mul(self.config.lockout_seconds))
+            .map_or(max, |v| v.min(max));
+        Duration::from_secs(seconds)
+    }
+}
+
+impl Failures {
+    fn expired(&self, now: Instant, window: Duration) -> bool {
+        now.duration_since(self.last) >= window && self.locked_until.is_none_or(|v| v <= now)
+    }
+}
+
+impl Key {
+    fn limit(&self, config: &config::Login) -> u32 {
+        match self {
+            Key::Ip(_) => config.ip_attempts,
+            Key::Account(_) => config.account_attempts,
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Ip(ip) => write!(f, "address {ip}"),
+            Key::Account(email) => write!(f, "account {email}"),
+        }
+    }
+}
+
+fn keys(ip: &str, account: Option<&str>) -> Vec<Key> {
+    let mut keys = vec![Key::Ip(ip.to_string())];
+    keys.extend(account.map(|v| Key::Account(v.to_string())));
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_doubles() {
+        let limiter = Limiter::new(&config::Login::default());
+        for (over, seconds) in [(1, 30), (2, 60), (3, 120), (4, 240), (7, 1920)] {
+            assert_eq!(
+                limiter.lockout(over),
+                Duration::from_secs(seconds),
+                "{over} over"
+            );
+        }
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        let limiter = Limiter::new(&config::Login::default());
+        let max = Duration::from_secs(60 * 60);
+        assert_eq!(limiter.lockout(8), max);
+        // would overflow without the cap
+        assert_eq!(limiter.lockout(70), max);
+        assert_eq!(limiter.lockout(u32::MAX), max);
+    }
+
+    #[test]
+    fn failures_expire_after_the_window() {
+        let window = Duration::from_secs(15 * 60);
+        let last = Instant::now();
+        let mut failures = Failures {
+            count: 3,
+            last,
+            locked_until: None,
+        };
+        assert!(!failures.expired(last + window / 2, window));
+        assert!(failures.expired(last + window, window));
+
+        // not while still locked out
+        failures.locked_until = Some(last + window * 2);
+        assert!(!failures.expired(last + window, window));
+        assert!(failures.expired(last + window * 2, window));
+    }
+
+    #[test]
+    fn reset_only_forgets_the_account() {
+        let limiter = Limiter::new(&config::Login {
+            ip_attempts: 1,
+            account_attempts: 1,
+            ..config::Login::default()
+        });
+        let account = Some("alice@example.com");
+        limiter.fail("10.0.0.1", account);
+        assert!(limiter.check("10.0.0.1", account).is_ok());
+        limiter.fail("10.0.0.1", account);
+        assert!(limiter.check("10.0.0.2", account).is_err());
+        assert!(limiter.check("10.0.0.1", None).is_err());
+
+        limiter.reset("alice@example.com");
+        assert!(limiter.check("10.0.0.2", account).is_ok());
+        assert!(limiter.check("10.0.0.1", account).is_err());
+        assert!(limiter.check("10.0.0.1", None).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use crate::config;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+mod transport;
+
+pub use transport::{FileDrop, MemoryMailer, Smtp};
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers mails to users, the transport is picked by `mail.transport`.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()>;
+}
+
+pub fn connect(config: &config::Mail) -> anyhow::Result<Arc<dyn Mailer>> {
+    Ok(match config.transport {
+        config::Transport::Smtp => Arc::new(Smtp::new(config)?),
+        config::Transport::File => Arc::new(FileDrop::new(config)?),
+        config::Transport::Memory => Arc::new(MemoryMailer::new(config)?),
+    })
+}
+
+pub fn verification(config: &config::Mail, to: &str, token: &str) -> Mail {
+    Mail {
+        to: to.to_string(),
+        subject: "Verify your email".into(),
+        body: format!(
+            "Welcome to brass!\n\n\
+             Open this link to verify your email, it works for {} hours:\n\n\
+             {}/api/verify/{token}\n\n\
+             If you didn't sign up you can ignore this mail.\n",
+            config.verify_hours,
+            config.base_url.trim_end_matches('/'),
+        ),
+    }
+}
+
+pub fn reset(config: &config::Mail, to: &str, token: &str) -> Mail {
+    Mail {
+        to: to.to_string(),
+        subject: "Reset your password".into(),
+        body: format!(
+            "Someone asked to reset the password of your brass account.\n\n\
+             Open this link to choose a new one, it works for {} minutes:\n\n\
+             {}/reset?token={token}\n\n\
+             If that wasn't you, ignore this mail and your password stays as it is.\n",
+            config.reset_minutes,
+            config.base_url.trim_end_matches('/'),
+        ),
+    }
+}
@@ -0,0 +1,114 @@
+use super::{Mail, Mailer};
+use crate::config;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{fs, path::PathBuf};
+
+/// Hands mails to an SMTP server.
+pub struct Smtp {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+/// Writes every mail as an `.eml` file into a directory, for development.
+pub struct FileDrop {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+/// Keeps mails in memory and logs them, nothing leaves the process.
+pub struct MemoryMailer {
+    from: Mailbox,
+}
+
+impl Smtp {
+    pub fn new(config: &config::Mail) -> anyhow::Result<Smtp> {
+        let mut builder = if config.smtp_starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host)
+        }
+        .port(config.smtp_port);
+        if !config.smtp_username.is_empty() {
+            builder = builder.credentials(Credentials::new(
+                config.smtp_username.clone(),
+                config.smtp_password.clone(),
+            ));
+        }
+        Ok(Smtp {
+            from: sender(config)?,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl FileDrop {
+    pub fn new(config: &config::Mail) -> anyhow::Result<FileDrop> {
+        fs::create_dir_all(&config.drop_dir)?;
+        Ok(FileDrop {
+            from: sender(config)?,
+            dir: PathBuf::from(&config.drop_dir),
+        })
+    }
+}
+
+impl MemoryMailer {
+    pub fn new(config: &config::Mail) -> anyhow::Result<MemoryMailer> {
+        Ok(MemoryMailer {
+            from: sender(config)?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for Smtp {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+        self.transport.send(message(&self.from, &mail)?).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Mailer for FileDrop {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+        let name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S"),
+            uuid::Uuid::new_v4()
+        );
+        fs::write(self.dir.join(name), message(&self.from, &mail)?.formatted())?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+        // checks the mail like the other transports would
+        message(&self.from, &mail)?;
+        log::info!("mail to {} : {}\n{}", mail.to, mail.subject, mail.body);
+        Ok(())
+    }
+}
+
+fn sender(config: &config::Mail) -> anyhow::Result<Mailbox> {
+    config
+        .from
+        .parse()
+        .map_err(|err| anyhow!("mail.from isn't a valid address : {err}"))
+}
+
+fn message(from: &Mailbox, mail: &Mail) -> anyhow::Result<Message> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .to(mail.to.parse()?)
+        .subject(&mail.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(mail.body.clone())?)
+}
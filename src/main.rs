@@ -1,106 +1,163 @@
+mod config;
+mod cookie;
 mod cryption;
 mod data;
+mod error;
+mod limit;
+mod mail;
+mod protocol;
 mod routes;
 mod server;
 mod session;
 mod table;
+mod token;
+mod totp;
 mod utils;
+mod validate;
 
 use routes::*;
 
 use actix::*;
 use actix_files::Files;
-use actix_session::{
-    config::PersistentSession, storage::CookieSessionStore, Session, SessionMiddleware,
-};
-use actix_web::{cookie::Key, web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_session::Session;
+use actix_web::{dev::Service, web, App, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
-use serde_json::json;
 
 async fn socket(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<server::ChatServer>>,
     session: Session,
-    db: web::Data<data::Database>,
-) -> HttpResponse {
-    if let Some(sid) = session.get::<String>("sid").unwrap_or(None) {
-        let username = match db.get_username(sid).await {
-            Ok(v) => v,
-            Err(err) => {
-                return HttpResponse::Forbidden()
-                    .body(json!({ "error": err.to_string() }).to_string())
-            }
-        };
-        let ws = ws::start(
-            session::SocketSession {
-                username,
-                addr: srv.get_ref().clone(),
-            },
-            &req,
-            stream,
-        );
-        match ws {
-            Ok(response) => response,
-            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
-        }
-    } else {
-        HttpResponse::Forbidden().body(
-            json!({
-                "error": "No Session"}
-            )
-            .to_string(),
-        )
-    }
+    db: web::Data<dyn data::Database>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let sid = sid(&session)?;
+    let username = db.get_username(sid.clone()).await?;
+    let id = db.get_id(sid.clone()).await?;
+    let record = db.get_session(sid).await?;
+    ws::start(
+        session::SocketSession {
+            id,
+            session: record,
+            conn: 0,
+            username,
+            addr: srv.get_ref().clone(),
+            db,
+        },
+        &req,
+        stream,
+    )
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    let server = web::Data::new(server::ChatServer::new().start());
-    let db: web::Data<data::Database> = web::Data::new(
-        data::Database::new("localhost:8000", None, None)
-            .await
-            .unwrap(),
+    let config = web::Data::new(config::Config::load().unwrap());
+    let db = web::Data::from(data::connect(&config).await.unwrap());
+    let mailer = web::Data::from(mail::connect(&config.mail).unwrap());
+    let keys = cookie::Keys::load(&config.session).unwrap();
+    let static_dir = config.server.static_dir.clone();
+    let (workers, host, port) = (
+        config.server.workers,
+        config.server.host.clone(),
+        config.server.port,
     );
+    let server = web::Data::new(server::ChatServer::new(db.clone()).start());
+    let limiter = web::Data::new(limit::Limiter::new(&config.login));
     HttpServer::new(move || {
         App::new()
             .app_data(server.clone())
             .app_data(db.clone())
+            .app_data(mailer.clone())
+            .app_data(limiter.clone())
+            .app_data(config.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(64 * 1024)
+                    .error_handler(error::bad_request),
+            )
+            .app_data(web::QueryConfig::default().error_handler(error::bad_request))
+            .app_data(web::PathConfig::default().error_handler(error::bad_request))
             .route("/ws", web::get().to(socket))
             .service(signup)
             .service(login)
+            .service(login_two_factor)
+            .service(verify)
+            .service(resend_verification)
+            .service(forgot_password)
+            .service(reset_password)
+            .service(change_password)
+            .service(change_email)
+            .service(delete_account)
+            .service(enroll_two_factor)
+            .service(confirm_two_factor)
+            .service(disable_two_factor)
+            .service(update_account)
+            .service(contacts)
+            .service(add_contact)
+            .service(accept_contact)
+            .service(decline_contact)
+            .service(cancel_contact)
+            .service(remove_contact)
+            .service(block_user)
+            .service(unblock_user)
             .service(get_data)
             .service(message)
             .service(get_chat)
+            .service(create_group)
+            .service(update_group)
+            .service(add_member)
+            .service(remove_member)
+            .service(leave_group)
+            .service(set_role)
+            .service(pin_message)
+            .service(unpin_message)
+            .service(delete_message)
+            .service(mute_chat)
+            .service(unmute_chat)
+            .service(sessions)
+            .service(revoke_session)
+            .service(revoke_sessions)
+            .service(logout)
             .service(
-                Files::new("/", "www/dist")
+                Files::new("/", &static_dir)
                     .prefer_utf8(true)
                     .index_file("index.html"),
             )
             .service(
-                Files::new("/home", "www/dist/home")
+                Files::new("/home", format!("{static_dir}/home"))
                     .prefer_utf8(true)
                     .index_file("index.html"),
             )
             .service(
-                Files::new("/auth", "www/dist/auth")
+                Files::new("/auth", format!("{static_dir}/auth"))
                     .prefer_utf8(true)
                     .index_file("index.html"),
             )
-            //            .wrap(Logger::default())
-            .wrap(
-                SessionMiddleware::builder(CookieSessionStore::default(), Key::from(&[0; 64]))
-                    .cookie_secure(false)
-                    // customize session and cookie expiration
-                    .session_lifecycle(
-                        PersistentSession::default(), //.session_ttl(actix_web::cookie::time::Duration::hours(2)),
-                    )
-                    .build(),
+            .service(
+                Files::new("/reset", format!("{static_dir}/reset"))
+                    .prefer_utf8(true)
+                    .index_file("index.html"),
             )
+            //            .wrap(Logger::default())
+            .wrap(cookie::middleware(&keys, &config.session))
+            // runs before the session middleware, cookies signed by the previous key get upgraded
+            .wrap_fn({
+                let (keys, config) = (keys.clone(), config.clone());
+                move |mut req, srv| {
+                    let upgraded = keys.upgrade(&config.session, &mut req);
+                    let res = srv.call(req);
+                    async move {
+                        let mut res = res.await?;
+                        if let Some(cookie) = upgraded {
+                            cookie::reissue(&mut res, cookie);
+                        }
+                        Ok(res)
+                    }
+                }
+            })
     })
-    .workers(4)
-    .bind(("0.0.0.0", 8080))?
+    .workers(workers)
+    .bind((host.as_str(), port))?
     .run()
     .await
 }
@@ -0,0 +1,97 @@
+use crate::table;
+use serde::{Deserialize, Serialize};
+
+pub const VERSION: u8 = 1;
+
+/// Every frame on `/ws` is a json object carrying the protocol version `v`
+/// next to the `type` tag of the frame.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Frame<T> {
+    pub v: u8,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// Sends `body` into `chat`, or into the direct chat with the user `to`.
+    Send {
+        id: String,
+        #[serde(default)]
+        chat: Option<String>,
+        #[serde(default)]
+        to: Option<String>,
+        body: String,
+    },
+    ListUsers,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Ack {
+        id: String,
+        chat: String,
+        message: String,
+    },
+    Error {
+        id: Option<String>,
+        code: String,
+        message: String,
+    },
+    Event(Box<Event>),
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Message {
+        chat: String,
+        from: String,
+        message: table::Message,
+        /// Set when the recipient muted the chat, the message shouldn't notify.
+        #[serde(default)]
+        muted: bool,
+    },
+    /// A user sharing a chat came online or went offline.
+    Presence { user: String, online: bool },
+    /// `from` asked to become a contact.
+    ContactRequest { from: String, username: String },
+    /// `user` accepted a contact request, or sent one that was accepted right away.
+    ContactAccepted { user: String, username: String },
+    /// The text of a message was removed.
+    Deleted { chat: String, message: String },
+}
+
+impl ServerFrame {
+    pub fn error(id: Option<String>, code: &str, message: impl ToString) -> ServerFrame {
+        ServerFrame::Error {
+            id,
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(&Frame {
+            v: VERSION,
+            body: self,
+        })
+        .unwrap_or_default()
+    }
+}
+
+pub fn parse(text: &str) -> Result<ClientFrame, ServerFrame> {
+    let frame: Frame<serde_json::Value> = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(err) => return Err(ServerFrame::error(None, "bad_frame", err)),
+    };
+    if frame.v != VERSION {
+        return Err(ServerFrame::error(
+            None,
+            "unsupported_version",
+            format!("protocol version {} isn't supported", frame.v),
+        ));
+    }
+    serde_json::from_value(frame.body).map_err(|err| ServerFrame::error(None, "bad_frame", err))
+}
@@ -1,84 +1,1151 @@
-use crate::data;
+use crate::error::{ApiError, Result};
+use crate::{config, data, limit, mail, server, table, totp, utils, validate};
+use actix::Addr;
 use actix_session::Session;
-use actix_web::{get, web, HttpResponse};
+use actix_web::{delete, get, patch, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::BTreeMap;
 
-#[get("/api/signup/{email}/{username}/{password}")]
+#[derive(Deserialize)]
+pub struct SignupForm {
+    email: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    email: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResendForm {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetForm {
+    token: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    current: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailForm {
+    email: String,
+    password: String,
+}
+
+/// For actions that only ask for the password to confirm it's really the user.
+#[derive(Deserialize)]
+pub struct PasswordCheck {
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorLoginForm {
+    token: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisableForm {
+    password: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct GroupForm {
+    title: String,
+    #[serde(default)]
+    topic: String,
+    #[serde(default)]
+    avatar: String,
+    /// User ids of the members besides the creator.
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+/// Fields that are left out stay as they are.
+#[derive(Deserialize)]
+pub struct GroupUpdateForm {
+    title: Option<String>,
+    topic: Option<String>,
+    avatar: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MemberForm {
+    user: String,
+}
+
+/// Settings that are left out stay as they are.
+#[derive(Deserialize)]
+pub struct AccountForm {
+    contacts_only: Option<bool>,
+    public_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ContactForm {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct MessageForm {
+    text: String,
+}
+
+#[derive(Deserialize)]
+pub struct RoleForm {
+    role: table::Role,
+}
+
+#[derive(Serialize)]
+pub struct AuthResponse {
+    username: String,
+}
+
+const FIELD_MAX: usize = 1024;
+
+impl SignupForm {
+    /// Checks every field and reports all that are wrong at once, the email
+    /// comes back normalized.
+    fn validate(self, policy: &config::Password) -> Result<SignupForm> {
+        required(&[
+            ("email", &self.email),
+            ("username", &self.username),
+            ("password", &self.password),
+        ])?;
+        let mut fields = BTreeMap::new();
+        let email = match validate::email(&self.email) {
+            Ok(v) => v,
+            Err(err) => {
+                fields.insert("email", format!("email {err}"));
+                String::new()
+            }
+        };
+        let username = match validate::username(&self.username) {
+            Ok(v) => v,
+            Err(err) => {
+                fields.insert("username", format!("username {err}"));
+                String::new()
+            }
+        };
+        if let Err(err) = validate::password(&self.password, policy) {
+            fields.insert("password", format!("password {err}"));
+        }
+        if !fields.is_empty() {
+            return Err(ApiError::Invalid(fields));
+        }
+        Ok(SignupForm {
+            email,
+            username,
+            password: self.password,
+        })
+    }
+}
+
+impl GroupForm {
+    fn validate(self) -> Result<GroupForm> {
+        let update = GroupUpdateForm {
+            title: Some(self.title),
+            topic: Some(self.topic),
+            avatar: Some(self.avatar),
+        }
+        .validate()?;
+        Ok(GroupForm {
+            title: update.title.unwrap_or_default(),
+            topic: update.topic.unwrap_or_default(),
+            avatar: update.avatar.unwrap_or_default(),
+            members: self.members,
+        })
+    }
+}
+
+impl GroupUpdateForm {
+    fn validate(self) -> Result<GroupUpdateForm> {
+        let mut fields = BTreeMap::new();
+        let mut check =
+            |field: &'static str,
+             value: Option<String>,
+             f: fn(&str) -> std::result::Result<String, String>| {
+                value.and_then(|v| match f(&v) {
+                    Ok(v) => Some(v),
+                    Err(err) => {
+                        fields.insert(field, format!("{field} {err}"));
+                        None
+                    }
+                })
+            };
+        let form = GroupUpdateForm {
+            title: check("title", self.title, validate::title),
+            topic: check("topic", self.topic, validate::topic),
+            avatar: check("avatar", self.avatar, validate::avatar),
+        };
+        if !fields.is_empty() {
+            return Err(ApiError::Invalid(fields));
+        }
+        Ok(form)
+    }
+}
+
+impl LoginForm {
+    fn validate(self) -> Result<LoginForm> {
+        let form = LoginForm {
+            email: self.email.trim().to_lowercase(),
+            password: self.password,
+        };
+        required(&[("email", &form.email), ("password", &form.password)])?;
+        Ok(form)
+    }
+}
+
+fn required(fields: &[(&'static str, &String)]) -> Result<()> {
+    let mut invalid = BTreeMap::new();
+    for (name, value) in fields {
+        if value.trim().is_empty() {
+            invalid.insert(*name, format!("{name} is required"));
+        } else if value.len() > FIELD_MAX {
+            invalid.insert(*name, format!("{name} is too long"));
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(ApiError::Invalid(invalid));
+    }
+    Ok(())
+}
+
+fn invalid(field: &'static str, err: String) -> ApiError {
+    ApiError::Invalid(BTreeMap::from([(field, format!("{field} {err}"))]))
+}
+
+/// Returns the session id the cookie carries.
+pub fn sid(session: &Session) -> Result<String> {
+    session
+        .get("sid")
+        .unwrap_or(None)
+        .ok_or(ApiError::Unauthorized)
+}
+
+#[post("/api/signup")]
 pub async fn signup(
-    data: web::Path<(String, String, String)>,
-    db: web::Data<data::Database>,
-) -> HttpResponse {
-    let (email, username, password) = data.into_inner();
-    match db.signup(email, username, password).await {
-        Ok(_) => HttpResponse::Ok().body(""),
-        Err(err) => HttpResponse::Forbidden().body(json!({"error" : err.to_string()}).to_string()),
+    data: web::Json<SignupForm>,
+    db: web::Data<dyn data::Database>,
+    mailer: web::Data<dyn mail::Mailer>,
+    config: web::Data<config::Config>,
+) -> Result<HttpResponse> {
+    let form = data.into_inner().validate(&config.password)?;
+    let token = db
+        .signup(form.email.clone(), form.username.clone(), form.password)
+        .await?;
+    mailer
+        .send(mail::verification(&config.mail, &form.email, &token))
+        .await?;
+    Ok(HttpResponse::Ok().json(AuthResponse {
+        username: form.username,
+    }))
+}
+
+#[get("/api/verify/{token}")]
+pub async fn verify(
+    db: web::Data<dyn data::Database>,
+    data: web::Path<String>,
+) -> Result<HttpResponse> {
+    db.verify_email(data.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(json!({ "verified": true })))
+}
+
+/// Sends the verification mail again, for when the first one didn't arrive.
+/// Answers the same whether there is such an account or not.
+#[post("/api/verify/resend")]
+pub async fn resend_verification(
+    data: web::Json<ResendForm>,
+    db: web::Data<dyn data::Database>,
+    mailer: web::Data<dyn mail::Mailer>,
+    config: web::Data<config::Config>,
+) -> Result<HttpResponse> {
+    let email = data.into_inner().email.trim().to_lowercase();
+    required(&[("email", &email)])?;
+    match db.resend_verification(email.clone()).await {
+        Ok(Some(token)) => send_later(mailer, mail::verification(&config.mail, &email, &token)),
+        Ok(None) => (),
+        Err(err) => err.log(),
     }
+    Ok(HttpResponse::Ok().body(""))
+}
+
+// sends from a task of its own, so answering doesn't wait for the mail server
+fn send_later(mailer: web::Data<dyn mail::Mailer>, mail: mail::Mail) {
+    actix_web::rt::spawn(async move {
+        let subject = mail.subject.clone();
+        if let Err(err) = mailer.send(mail).await {
+            log::error!("couldn't send the mail \"{subject}\" : {err:#}");
+        }
+    });
 }
 
-#[get("/api/login/{email}/{password}")]
+#[post("/api/login")]
 pub async fn login(
-    data: web::Path<(String, String)>,
-    db: web::Data<data::Database>,
-    session: Session,
-) -> HttpResponse {
-    let (email, password) = data.into_inner();
-    match db.login(email, password).await {
-        Ok(sid) => {
-            if session.insert("sid", sid).is_ok() {
-                HttpResponse::Ok().body("session set")
-            } else {
-                HttpResponse::TemporaryRedirect().body("couldn't set your session id")
-            }
+    req: HttpRequest,
+    data: web::Json<LoginForm>,
+    db: web::Data<dyn data::Database>,
+    limiter: web::Data<limit::Limiter>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner().validate()?;
+    let (ip, email) = (utils::ip(&req), form.email.clone());
+    limiter.check(&ip, Some(&email))?;
+    let sid = match db
+        .login(
+            form.email,
+            form.password,
+            utils::user_agent(&req),
+            ip.clone(),
+        )
+        .await
+    {
+        Err(ApiError::InvalidCredentials) => {
+            limiter.fail(&ip, Some(&email));
+            return Err(ApiError::InvalidCredentials);
+        }
+        sid => sid?,
+    };
+    match sid {
+        data::Login::Session(sid) => {
+            limiter.reset(&email);
+            start_session(db, session, sid).await
+        }
+        // the client finishes the login at /api/login/2fa with this token
+        data::Login::TwoFactor(token) => {
+            Ok(HttpResponse::Ok().json(json!({ "two_factor": token })))
         }
-        Err(err) => HttpResponse::Forbidden().body(json!({"error" : err.to_string()}).to_string()),
     }
 }
 
-#[get("/api/getdata")]
-pub async fn get_data(db: web::Data<data::Database>, session: Session) -> HttpResponse {
-    match session.get("sid").unwrap_or(None) {
-        Some(sid) => match db.get_data(sid).await {
-            Ok(v) => HttpResponse::Ok().body(v),
-            Err(err) => {
-                HttpResponse::Forbidden().body(json!({ "error": err.to_string()}).to_string())
-            }
-        },
-        None => HttpResponse::Forbidden().body(json!({"error" : "no session id"}).to_string()),
+#[post("/api/login/2fa")]
+pub async fn login_two_factor(
+    req: HttpRequest,
+    data: web::Json<TwoFactorLoginForm>,
+    db: web::Data<dyn data::Database>,
+    limiter: web::Data<limit::Limiter>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("token", &form.token), ("code", &form.code)])?;
+    let ip = utils::ip(&req);
+    // the token doesn't name the account, wrong codes only count for the address
+    limiter.check(&ip, None)?;
+    let sid = match db
+        .login_two_factor(form.token, form.code, utils::user_agent(&req), ip.clone())
+        .await
+    {
+        Err(ApiError::InvalidCode) => {
+            limiter.fail(&ip, None);
+            return Err(ApiError::InvalidCode);
+        }
+        sid => sid?,
+    };
+    start_session(db, session, sid).await
+}
+
+async fn start_session(
+    db: web::Data<dyn data::Database>,
+    session: Session,
+    sid: String,
+) -> Result<HttpResponse> {
+    let username = db.get_username(sid.clone()).await?;
+    session
+        .insert("sid", sid)
+        .map_err(|err| anyhow::anyhow!("couldn't set the session id : {err}"))?;
+    Ok(HttpResponse::Ok().json(AuthResponse { username }))
+}
+
+/// Returns the secret to add to an authenticator app, two factor
+/// authentication is only turned on once a code of it is confirmed.
+#[post("/api/2fa/enroll")]
+pub async fn enroll_two_factor(
+    data: web::Json<PasswordCheck>,
+    db: web::Data<dyn data::Database>,
+    config: web::Data<config::Config>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("password", &form.password)])?;
+    let (secret, email) = db.enroll_two_factor(sid(&session)?, form.password).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "secret" : totp::base32(&secret),
+        "uri" : totp::uri(&config.two_factor.issuer, &email, &secret)
+    })))
+}
+
+/// Returns the recovery codes, they are shown only this once.
+#[post("/api/2fa/confirm")]
+pub async fn confirm_two_factor(
+    data: web::Json<CodeForm>,
+    db: web::Data<dyn data::Database>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("code", &form.code)])?;
+    let codes = db.confirm_two_factor(sid(&session)?, form.code).await?;
+    Ok(HttpResponse::Ok().json(json!({ "recovery_codes": codes })))
+}
+
+#[post("/api/2fa/disable")]
+pub async fn disable_two_factor(
+    data: web::Json<DisableForm>,
+    db: web::Data<dyn data::Database>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("password", &form.password), ("code", &form.code)])?;
+    db.disable_two_factor(sid(&session)?, form.password, form.code)
+        .await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+/// Answers the same whether the account exists or not, so it can't be used
+/// to find out who has one. The mail goes out in the background, waiting for
+/// it would give the answer away too.
+#[post("/api/password/forgot")]
+pub async fn forgot_password(
+    data: web::Json<ForgotForm>,
+    db: web::Data<dyn data::Database>,
+    mailer: web::Data<dyn mail::Mailer>,
+    config: web::Data<config::Config>,
+) -> Result<HttpResponse> {
+    let email = data.into_inner().email.trim().to_lowercase();
+    required(&[("email", &email)])?;
+    match db.forgot_password(email.clone()).await {
+        Ok(Some(token)) => send_later(mailer, mail::reset(&config.mail, &email, &token)),
+        Ok(None) => (),
+        Err(err) => err.log(),
     }
+    Ok(HttpResponse::Ok().body(""))
 }
-#[get("/api/message/{reciver}/{text}")]
-pub async fn message(
-    db: web::Data<data::Database>,
-    session: Session,
-    data: web::Path<(String, String)>,
-) -> HttpResponse {
-    let (reciver, text) = data.into_inner();
-    match session.get("sid").unwrap_or(None) {
-        Some(sid) => {
-            let owner = match db.get_id(sid).await {
-                Ok(v) => v,
-                Err(err) => {
-                    return HttpResponse::Forbidden()
-                        .body(json!({ "error": err.to_string()}).to_string());
-                }
-            };
-            let chat = match db.get_chat(vec![reciver, owner]).await {
-                Ok(v) => HttpResponse::Ok().body(v),
-                Err(err) => {
-                    HttpResponse::Forbidden().body(json!({ "error": err.to_string()}).to_string())
-                }
-            };
-            chat
+
+#[post("/api/password/reset")]
+pub async fn reset_password(
+    data: web::Json<ResetForm>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<config::Config>,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("token", &form.token), ("password", &form.password)])?;
+    validate::password(&form.password, &config.password).map_err(|err| invalid("password", err))?;
+    let ids = db.reset_password(form.token, form.password).await?;
+    srv.do_send(server::Revoke { sessions: ids });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[post("/api/account/password")]
+pub async fn change_password(
+    data: web::Json<PasswordForm>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<config::Config>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("current", &form.current), ("password", &form.password)])?;
+    validate::password(&form.password, &config.password).map_err(|err| invalid("password", err))?;
+    let ids = db
+        .change_password(sid(&session)?, form.current, form.password)
+        .await?;
+    srv.do_send(server::Revoke { sessions: ids });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+/// The account keeps its email until the link sent to the new one is opened.
+#[post("/api/account/email")]
+pub async fn change_email(
+    data: web::Json<EmailForm>,
+    db: web::Data<dyn data::Database>,
+    mailer: web::Data<dyn mail::Mailer>,
+    config: web::Data<config::Config>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("email", &form.email), ("password", &form.password)])?;
+    let email = validate::email(&form.email).map_err(|err| invalid("email", err))?;
+    let token = db
+        .change_email(sid(&session)?, form.password, email.clone())
+        .await?;
+    mailer
+        .send(mail::verification(&config.mail, &email, &token))
+        .await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[delete("/api/account")]
+pub async fn delete_account(
+    data: web::Json<PasswordCheck>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("password", &form.password)])?;
+    let sid = sid(&session)?;
+    let (user, sender) = (
+        db.get_id(sid.clone()).await?,
+        db.get_username(sid.clone()).await?,
+    );
+    let (ids, chats) = db.delete_account(sid, form.password).await?;
+    for (chat, notice) in chats {
+        srv.do_send(server::Leave {
+            chat: chat.clone(),
+            id: user.clone(),
+        });
+        if let Some(notice) = notice {
+            srv.do_send(server::SystemMessage {
+                sender: sender.clone(),
+                chat,
+                message: notice,
+            });
         }
-        None => HttpResponse::Forbidden().body(json!({"error" : "no session id"}).to_string()),
     }
+    srv.do_send(server::Revoke { sessions: ids });
+    session.purge();
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[patch("/api/account")]
+pub async fn update_account(
+    data: web::Json<AccountForm>,
+    db: web::Data<dyn data::Database>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    let public_key = form
+        .public_key
+        .map(|v| validate::public_key(&v))
+        .transpose()
+        .map_err(|err| invalid("public_key", err))?;
+    db.update_account(sid(&session)?, form.contacts_only, public_key)
+        .await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+/// Returns the contacts and the open contact requests of the user.
+#[get("/api/contacts")]
+pub async fn contacts(db: web::Data<dyn data::Database>, session: Session) -> Result<HttpResponse> {
+    let user = db.get_id(sid(&session)?).await?;
+    Ok(HttpResponse::Ok().body(db.list_contacts(user).await?))
+}
+
+/// Sends a contact request, or accepts the one the other user already sent.
+#[post("/api/contacts")]
+pub async fn add_contact(
+    data: web::Json<ContactForm>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("username", &form.username)])?;
+    let sid = sid(&session)?;
+    let (user, username) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    let (recipient, accepted) = match db.request_contact(user.clone(), form.username).await? {
+        data::Request::Sent(id) => (id, false),
+        data::Request::Accepted(id) => (id, true),
+    };
+    srv.do_send(server::ContactAdd {
+        requester: user,
+        username,
+        recipient,
+        accepted,
+    });
+    Ok(HttpResponse::Ok().json(json!({ "accepted": accepted })))
+}
+
+#[post("/api/contacts/requests/{user}/accept")]
+pub async fn accept_contact(
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let requester = path.into_inner();
+    let sid = sid(&session)?;
+    let (user, username) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    db.answer_contact(user.clone(), requester.clone(), true)
+        .await?;
+    // the one who asked learns it was accepted
+    srv.do_send(server::ContactAdd {
+        requester: user,
+        username,
+        recipient: data::user_id(requester),
+        accepted: true,
+    });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[post("/api/contacts/requests/{user}/decline")]
+pub async fn decline_contact(
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let user = db.get_id(sid(&session)?).await?;
+    db.answer_contact(user, path.into_inner(), false).await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[delete("/api/contacts/requests/{user}")]
+pub async fn cancel_contact(
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let user = db.get_id(sid(&session)?).await?;
+    db.cancel_contact(user, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[delete("/api/contacts/{user}")]
+pub async fn remove_contact(
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let user = db.get_id(sid(&session)?).await?;
+    db.remove_contact(user, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+/// Blocks a user, they can't reach the user anymore and their messages aren't
+/// delivered to it.
+#[post("/api/blocks/{user}")]
+pub async fn block_user(
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    block(path.into_inner(), db, srv, session, true).await
+}
+
+#[delete("/api/blocks/{user}")]
+pub async fn unblock_user(
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    block(path.into_inner(), db, srv, session, false).await
+}
+
+async fn block(
+    other: String,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+    block: bool,
+) -> Result<HttpResponse> {
+    let user = db.get_id(sid(&session)?).await?;
+    db.block_user(user.clone(), other.clone(), block).await?;
+    srv.do_send(server::Block {
+        user,
+        other: data::user_id(other),
+        block,
+    });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[get("/api/getdata")]
+pub async fn get_data(db: web::Data<dyn data::Database>, session: Session) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(db.get_data(sid(&session)?).await?))
+}
+/// Sends a message to the direct chat with `reciver` and delivers it to the
+/// members that are online, returns the id of the chat.
+#[post("/api/message/{reciver}")]
+pub async fn message(
+    reciver: web::Path<String>,
+    data: web::Json<MessageForm>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let text = data.into_inner().text;
+    if text.trim().is_empty() {
+        return Err(ApiError::BadRequest("text can't be empty".into()));
+    }
+    let sid = sid(&session)?;
+    let (owner, sender) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    let chat = db
+        .get_chat(vec![reciver.into_inner(), owner.clone()])
+        .await?;
+    db.check_permission(chat.clone(), owner.clone(), table::Permission::Send)
+        .await?;
+    let msg = table::Message::new(text, owner);
+    let chat = db.insert_to_chat(chat, msg.clone()).await?;
+    srv.do_send(server::ClientMessage {
+        conn: None,
+        sender,
+        chat: chat.clone(),
+        message: msg,
+    });
+    Ok(HttpResponse::Ok().body(chat))
+}
+
+/// Creates a group of the user and the given members, returns its id.
+#[post("/api/groups")]
+pub async fn create_group(
+    data: web::Json<GroupForm>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner().validate()?;
+    let sid = sid(&session)?;
+    let (owner, sender) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    let (chat, notice) = db
+        .create_group(
+            owner,
+            form.title,
+            Some(form.topic).filter(|v| !v.is_empty()),
+            Some(form.avatar).filter(|v| !v.is_empty()),
+            form.members,
+        )
+        .await?;
+    srv.do_send(server::SystemMessage {
+        sender,
+        chat: chat.clone(),
+        message: notice,
+    });
+    Ok(HttpResponse::Ok().json(json!({ "id": chat })))
+}
+
+#[patch("/api/groups/{id}")]
+pub async fn update_group(
+    data: web::Json<GroupUpdateForm>,
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner().validate()?;
+    let chat = data::chat_id(path.into_inner());
+    let sid = sid(&session)?;
+    let (user, sender) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    let notice = db
+        .update_group(chat.clone(), user, form.title, form.topic, form.avatar)
+        .await?;
+    srv.do_send(server::SystemMessage {
+        sender,
+        chat,
+        message: notice,
+    });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[post("/api/groups/{id}/members")]
+pub async fn add_member(
+    data: web::Json<MemberForm>,
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let form = data.into_inner();
+    required(&[("user", &form.user)])?;
+    let chat = data::chat_id(path.into_inner());
+    let sid = sid(&session)?;
+    let (user, sender) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    let notice = db.add_member(chat.clone(), user, form.user.clone()).await?;
+    // joined first, so the new member gets the message too
+    srv.do_send(server::Join {
+        chat: chat.clone(),
+        id: data::user_id(form.user),
+    });
+    srv.do_send(server::SystemMessage {
+        sender,
+        chat,
+        message: notice,
+    });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[delete("/api/groups/{id}/members/{user}")]
+pub async fn remove_member(
+    path: web::Path<(String, String)>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let (chat, member) = path.into_inner();
+    let chat = data::chat_id(chat);
+    let sid = sid(&session)?;
+    let (user, sender) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    let notice = db.remove_member(chat.clone(), user, member.clone()).await?;
+    // the removed member still learns about it before leaving the room
+    srv.do_send(server::SystemMessage {
+        sender,
+        chat: chat.clone(),
+        message: notice,
+    });
+    srv.do_send(server::Leave {
+        chat,
+        id: data::user_id(member),
+    });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[post("/api/groups/{id}/leave")]
+pub async fn leave_group(
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let chat = data::chat_id(path.into_inner());
+    let sid = sid(&session)?;
+    let (user, sender) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    let notice = db.leave_group(chat.clone(), user.clone()).await?;
+    srv.do_send(server::SystemMessage {
+        sender,
+        chat: chat.clone(),
+        message: notice,
+    });
+    srv.do_send(server::Leave { chat, id: user });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[patch("/api/groups/{id}/members/{user}")]
+pub async fn set_role(
+    data: web::Json<RoleForm>,
+    path: web::Path<(String, String)>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let (chat, member) = path.into_inner();
+    let chat = data::chat_id(chat);
+    let sid = sid(&session)?;
+    let (user, sender) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    let notice = db
+        .set_role(chat.clone(), user, member, data.into_inner().role)
+        .await?;
+    srv.do_send(server::SystemMessage {
+        sender,
+        chat,
+        message: notice,
+    });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[post("/api/chats/{id}/pins/{message}")]
+pub async fn pin_message(
+    path: web::Path<(String, String)>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    pin(path.into_inner(), db, srv, session, true).await
+}
+
+#[delete("/api/chats/{id}/pins/{message}")]
+pub async fn unpin_message(
+    path: web::Path<(String, String)>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    pin(path.into_inner(), db, srv, session, false).await
+}
+
+async fn pin(
+    (chat, id): (String, String),
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+    pinned: bool,
+) -> Result<HttpResponse> {
+    let chat = data::chat_id(chat);
+    let sid = sid(&session)?;
+    let (user, sender) = (db.get_id(sid.clone()).await?, db.get_username(sid).await?);
+    let notice = db.pin_message(chat.clone(), user, id, pinned).await?;
+    srv.do_send(server::SystemMessage {
+        sender,
+        chat,
+        message: notice,
+    });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+/// Mutes a chat, its messages still arrive but don't notify.
+#[post("/api/chats/{id}/mute")]
+pub async fn mute_chat(
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    mute(path.into_inner(), db, srv, session, true).await
+}
+
+#[delete("/api/chats/{id}/mute")]
+pub async fn unmute_chat(
+    path: web::Path<String>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    mute(path.into_inner(), db, srv, session, false).await
+}
+
+async fn mute(
+    chat: String,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+    mute: bool,
+) -> Result<HttpResponse> {
+    let chat = data::chat_id(chat);
+    let user = db.get_id(sid(&session)?).await?;
+    db.mute_chat(chat.clone(), user.clone(), mute).await?;
+    srv.do_send(server::Mute { user, chat, mute });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+/// Deletes the text of one of the user's messages, or of someone else's when
+/// their role allows it.
+#[delete("/api/chats/{id}/messages/{message}")]
+pub async fn delete_message(
+    path: web::Path<(String, String)>,
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let (chat, id) = path.into_inner();
+    let chat = data::chat_id(chat);
+    let user = db.get_id(sid(&session)?).await?;
+    db.delete_message(chat.clone(), user, id.clone()).await?;
+    srv.do_send(server::Deleted { chat, message: id });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[derive(Deserialize)]
+pub struct History {
+    before: Option<String>,
+    after: Option<String>,
+    limit: Option<usize>,
 }
 
 #[get("/api/get_chat/{id}")]
 pub async fn get_chat(
-    db: web::Data<data::Database>,
+    db: web::Data<dyn data::Database>,
+    session: Session,
+    data: web::Path<String>,
+    query: web::Query<History>,
+) -> Result<HttpResponse> {
+    let History {
+        before,
+        after,
+        limit,
+    } = query.into_inner();
+    let user = db.get_id(sid(&session)?).await?;
+    let chat = db
+        .get_messages(data.into_inner(), user, before, after, limit)
+        .await?;
+    Ok(HttpResponse::Ok().body(chat))
+}
+
+#[get("/api/sessions")]
+pub async fn sessions(db: web::Data<dyn data::Database>, session: Session) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(db.list_sessions(sid(&session)?).await?))
+}
+
+#[delete("/api/sessions/{id}")]
+pub async fn revoke_session(
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
     session: Session,
     data: web::Path<String>,
-) -> HttpResponse {
-    unreachable!()
+) -> Result<HttpResponse> {
+    let id = data.into_inner();
+    db.revoke_session(sid(&session)?, id.clone()).await?;
+    srv.do_send(server::Revoke { sessions: vec![id] });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[delete("/api/sessions")]
+pub async fn revoke_sessions(
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let ids = db.revoke_sessions(sid(&session)?).await?;
+    srv.do_send(server::Revoke { sessions: ids });
+    session.purge();
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[post("/api/logout")]
+pub async fn logout(
+    db: web::Data<dyn data::Database>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    session: Session,
+) -> Result<HttpResponse> {
+    let sid = sid(&session);
+    // the cookie goes away even if the server side session is already gone
+    session.purge();
+    let id = db.logout(sid?).await?;
+    srv.do_send(server::Revoke { sessions: vec![id] });
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cookie, error, mail::Mail};
+    use actix::Actor;
+    use actix_web::{cookie::Cookie, http::StatusCode, test, App};
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps what `MemoryMailer` sends, so the tokens in the mails can be used.
+    struct Inbox {
+        mailer: mail::MemoryMailer,
+        mails: Mutex<Vec<Mail>>,
+    }
+
+    #[async_trait::async_trait]
+    impl mail::Mailer for Inbox {
+        async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+            self.mailer.send(mail.clone()).await?;
+            self.mails.lock().unwrap().push(mail);
+            Ok(())
+        }
+    }
+
+    impl Inbox {
+        // the token of the last verification link that went to `to`
+        fn token(&self, to: &str) -> String {
+            let mails = self.mails.lock().unwrap();
+            let mail = mails.iter().rev().find(|m| m.to == to).unwrap();
+            let link = mail.body.split("/api/verify/").nth(1).unwrap();
+            link.split_whitespace().next().unwrap().to_string()
+        }
+    }
+
+    #[actix_web::test]
+    async fn signup_verify_login_and_chat() {
+        let mut config = config::Config::default();
+        config.database.uri = "memory".into();
+        config.session.secure = false;
+        let db: Arc<dyn data::Database> =
+            Arc::new(data::MemoryDatabase::memory(&config).await.unwrap());
+        let db = web::Data::from(db);
+        let inbox = Arc::new(Inbox {
+            mailer: mail::MemoryMailer::new(&config.mail).unwrap(),
+            mails: Mutex::new(vec![]),
+        });
+        let config = web::Data::new(config);
+        let keys = cookie::Keys {
+            current: actix_web::cookie::Key::generate(),
+            previous: None,
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(server::ChatServer::new(db.clone()).start()))
+                .app_data(db.clone())
+                .app_data(web::Data::from(inbox.clone() as Arc<dyn mail::Mailer>))
+                .app_data(web::Data::new(limit::Limiter::new(&config.login)))
+                .app_data(config.clone())
+                .service(signup)
+                .service(verify)
+                .service(login)
+                .service(get_data)
+                .service(message)
+                .service(get_chat)
+                .app_data(web::QueryConfig::default().error_handler(error::bad_request))
+                .wrap(cookie::middleware(&keys, &config.session)),
+        )
+        .await;
+
+        let mut cookies = vec![];
+        for (email, username) in [("alice@example.com", "alice"), ("bob@example.com", "bob")] {
+            let password = "correct horse battery";
+            let req = test::TestRequest::post()
+                .uri("/api/signup")
+                .set_json(json!({ "email": email, "username": username, "password": password }));
+            let res = test::call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let credentials = json!({ "email": email, "password": password });
+            let req = test::TestRequest::post()
+                .uri("/api/login")
+                .set_json(&credentials);
+            let res: serde_json::Value =
+                test::read_body_json(test::call_service(&app, req.to_request()).await).await;
+            assert_eq!(res["code"], "email_not_verified");
+
+            let uri = format!("/api/verify/{}", inbox.token(email));
+            let res =
+                test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let req = test::TestRequest::post()
+                .uri("/api/login")
+                .set_json(&credentials);
+            let res = test::call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let cookie: Cookie = res.response().cookies().next().unwrap().into_owned();
+            cookies.push(cookie);
+        }
+        let (alice, bob) = (&cookies[0], &cookies[1]);
+
+        let req = test::TestRequest::get()
+            .uri("/api/getdata")
+            .cookie(bob.clone());
+        let data: serde_json::Value =
+            test::read_body_json(test::call_service(&app, req.to_request()).await).await;
+        let uri = format!("/api/message/{}", data["id"].as_str().unwrap());
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .cookie(alice.clone())
+            .set_json(json!({ "text": "hello" }));
+        let chat = test::read_body(test::call_service(&app, req.to_request()).await).await;
+        let chat = String::from_utf8(chat.to_vec()).unwrap();
+
+        let uri = format!("/api/get_chat/{chat}");
+        let req = test::TestRequest::get().uri(&uri).cookie(bob.clone());
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let history: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(history["messages"][0]["text"], "hello");
+        assert_eq!(history["members"].as_array().unwrap().len(), 2);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("{uri}?limit=many"))
+            .cookie(bob.clone());
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(res["code"], "bad_request");
+
+        let req = test::TestRequest::get().uri(&uri);
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
 }
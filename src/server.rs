@@ -1,21 +1,54 @@
+use crate::{data, error::ApiError, protocol, table};
 use actix::prelude::*;
-use std::collections::HashMap;
+use actix_web::web;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Message(pub String);
 
+/// Tells a socket to close itself.
 #[derive(Message)]
-#[rtype(String)]
+#[rtype(result = "()")]
+pub struct Close;
+
+#[derive(Message)]
+#[rtype(usize)]
 pub struct Connect {
+    pub id: String,
     pub username: String,
+    /// record id of the login session the socket was opened with
+    pub session: String,
     pub addr: Recipient<Message>,
+    pub close: Recipient<Close>,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Disconnect {
-    pub username: String,
+    pub id: String,
+    pub conn: usize,
+}
+
+/// Closes every socket opened with one of the given login sessions.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Revoke {
+    pub sessions: Vec<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Join {
+    pub chat: String,
+    pub id: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    pub chat: String,
+    pub id: String,
 }
 
 #[derive(Message)]
@@ -25,27 +58,175 @@ pub struct ListUsers;
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ClientMessage {
-    pub text: String,
-    pub resiver: String,
+    /// Socket the message came from, it isn't echoed back there.
+    pub conn: Option<usize>,
     pub sender: String,
+    pub chat: String,
+    pub message: table::Message,
 }
 
+/// A message the server wrote into a chat, like the one about a new member.
+/// It goes to every online member.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SystemMessage {
+    /// Username of whoever made the change.
+    pub sender: String,
+    pub chat: String,
+    pub message: table::Message,
+}
+
+/// Tells the online members of a chat a message was deleted.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Deleted {
+    pub chat: String,
+    pub message: String,
+}
+
+/// Tells `recipient` about a contact request of `requester`, or that
+/// `requester` accepted one of theirs.
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ContactAdd {
     pub requester: String,
+    pub username: String,
+    pub recipient: String,
+    pub accepted: bool,
+}
+
+/// Updates who `user` blocked while it is online.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Block {
+    pub user: String,
+    pub other: String,
+    pub block: bool,
+}
+
+/// Updates which chats `user` muted while it is online.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Mute {
+    pub user: String,
+    pub chat: String,
+    pub mute: bool,
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    pub session: String,
+    pub addr: Recipient<Message>,
+    pub close: Recipient<Close>,
 }
 
 pub struct ChatServer {
-    pub session: HashMap<String, Recipient<Message>>,
+    // user id -> connection id -> socket, a user can be online on several devices
+    pub session: HashMap<String, HashMap<usize, Connection>>,
+    // chat id -> user ids of its members
+    pub rooms: HashMap<String, HashSet<String>>,
+    // user id -> users it blocked, and chats it muted, of online users
+    blocked: HashMap<String, HashSet<String>>,
+    muted: HashMap<String, HashSet<String>>,
+    next_conn: usize,
+    db: web::Data<dyn data::Database>,
 }
 
 impl ChatServer {
-    pub fn new() -> ChatServer {
+    pub fn new(db: web::Data<dyn data::Database>) -> ChatServer {
         ChatServer {
             session: HashMap::new(),
+            rooms: HashMap::new(),
+            blocked: HashMap::new(),
+            muted: HashMap::new(),
+            next_conn: 0,
+            db,
+        }
+    }
+    // sends a frame to every online member of a loaded chat, except for the
+    // socket `skip` of a user. `frame` picks what a member gets, if anything.
+    fn broadcast(
+        &self,
+        chat: &str,
+        skip: Option<(&str, usize)>,
+        frame: impl Fn(&str) -> Option<String>,
+    ) {
+        let Some(members) = self.rooms.get(chat) else {
+            return;
+        };
+        for member in members.iter() {
+            let Some(conns) = self.session.get(member) else {
+                continue;
+            };
+            let Some(frame) = frame(member) else {
+                continue;
+            };
+            for (conn, connection) in conns.iter() {
+                if skip != Some((member.as_str(), *conn)) {
+                    connection.addr.do_send(Message(frame.clone()));
+                }
+            }
+        }
+    }
+    fn send(&self, user: &str, frame: &str) {
+        for connection in self.session.get(user).into_iter().flat_map(|c| c.values()) {
+            connection.addr.do_send(Message(frame.to_string()));
+        }
+    }
+    /// Whether one of two online users blocked the other.
+    fn hidden(&self, a: &str, b: &str) -> bool {
+        let blocks =
+            |user: &str, other: &str| self.blocked.get(user).is_some_and(|v| v.contains(other));
+        blocks(a, b) || blocks(b, a)
+    }
+    // online users sharing a chat with `user`
+    fn peers(&self, user: &str) -> HashSet<String> {
+        self.rooms
+            .values()
+            .filter(|members| members.contains(user))
+            .flatten()
+            .filter(|m| *m != user && self.session.contains_key(*m))
+            .cloned()
+            .collect()
+    }
+    /// Tells the peers of `user` it came online or went offline, and an online
+    /// `user` who of them is online. Blocked users don't see each other.
+    fn announce(&self, user: &str, online: bool) {
+        for peer in self.peers(user) {
+            if self.hidden(user, &peer) {
+                continue;
+            }
+            self.send(&peer, &presence(user, online));
+            if online {
+                self.send(user, &presence(&peer, true));
+            }
         }
     }
+    /// Runs `then` once the members of `chat` are known, they are loaded when
+    /// nobody online had it open yet.
+    fn with_room(
+        &mut self,
+        chat: String,
+        ctx: &mut Context<Self>,
+        then: impl FnOnce(&mut ChatServer) + 'static,
+    ) {
+        if self.rooms.contains_key(&chat) {
+            then(self);
+            return;
+        }
+        let db = self.db.clone();
+        let id = chat.clone();
+        async move { db.get_members(id).await }
+            .into_actor(self)
+            .map(move |res, act, _| match res {
+                Ok(members) => {
+                    act.rooms.insert(chat, members.into_iter().collect());
+                    then(act);
+                }
+                Err(err) => log::warn!("no such chat as : {chat} ({err})"),
+            })
+            .wait(ctx);
+    }
 }
 
 impl Actor for ChatServer {
@@ -53,33 +234,227 @@ impl Actor for ChatServer {
 }
 
 impl Handler<Connect> for ChatServer {
-    type Result = String;
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> String {
-        self.session.insert(msg.username.clone(), msg.addr);
-        println!("new user : {}", msg.username);
-        msg.username
+    type Result = usize;
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> usize {
+        let conn = self.next_conn;
+        self.next_conn = self.next_conn.wrapping_add(1);
+        self.session.entry(msg.id.clone()).or_default().insert(
+            conn,
+            Connection {
+                session: msg.session,
+                addr: msg.addr,
+                close: msg.close,
+            },
+        );
+        println!("new connection {conn} of user : {}", msg.username);
+        let db = self.db.clone();
+        let id = msg.id.clone();
+        let load = async move {
+            let rooms = db.get_rooms(id.clone()).await?;
+            Ok::<_, ApiError>((rooms, db.get_filters(id).await?))
+        };
+        load.into_actor(self)
+            .map(move |res, act, _| {
+                // the socket may have closed while loading, nothing is kept for it then
+                let open = act
+                    .session
+                    .get(&msg.id)
+                    .is_some_and(|c| c.contains_key(&conn));
+                match res {
+                    _ if !open => {}
+                    Ok((rooms, (blocked, muted))) => {
+                        // only the first socket whose load finishes announces the user
+                        let first = !act.blocked.contains_key(&msg.id);
+                        for (chat, members) in rooms {
+                            act.rooms.insert(chat, members.into_iter().collect());
+                        }
+                        act.blocked
+                            .insert(msg.id.clone(), blocked.into_iter().collect());
+                        act.muted
+                            .insert(msg.id.clone(), muted.into_iter().collect());
+                        if first {
+                            act.announce(&msg.id, true);
+                        }
+                    }
+                    Err(err) => log::warn!("couldn't load chats : {err}"),
+                }
+            })
+            .spawn(ctx);
+        conn
     }
 }
 
 impl Handler<Disconnect> for ChatServer {
     type Result = ();
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        self.session.remove(&msg.username);
+        if let Some(conns) = self.session.get_mut(&msg.id) {
+            conns.remove(&msg.conn);
+            if conns.is_empty() {
+                self.session.remove(&msg.id);
+                // users whose sockets closed before they were loaded weren't announced
+                if self.blocked.contains_key(&msg.id) {
+                    self.announce(&msg.id, false);
+                }
+                self.blocked.remove(&msg.id);
+                self.muted.remove(&msg.id);
+            }
+        }
+        // forget rooms nobody online is a member of, they are loaded again on demand
+        let session = &self.session;
+        self.rooms
+            .retain(|_, members| members.iter().any(|m| session.contains_key(m)));
+    }
+}
+
+impl Handler<Revoke> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: Revoke, _: &mut Context<Self>) {
+        // the sockets disconnect themselves once they stopped
+        for connection in self.session.values().flat_map(|conns| conns.values()) {
+            if msg.sessions.contains(&connection.session) {
+                connection.close.do_send(Close);
+            }
+        }
+    }
+}
+
+impl Handler<Join> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
+        if let Some(members) = self.rooms.get_mut(&msg.chat) {
+            members.insert(msg.id);
+        }
+    }
+}
+
+impl Handler<Leave> for ChatServer {
+    type Result = ();
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) {
+        if let Some(members) = self.rooms.get_mut(&msg.chat) {
+            members.remove(&msg.id);
+        }
     }
 }
 
 impl Handler<ClientMessage> for ChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        if let Some(addr) = self.session.get(&msg.resiver) {
-            addr.do_send(Message(msg.text));
+    fn handle(&mut self, msg: ClientMessage, ctx: &mut Context<Self>) {
+        let frame = |muted| {
+            protocol::ServerFrame::Event(Box::new(protocol::Event::Message {
+                chat: msg.chat.clone(),
+                from: msg.sender.clone(),
+                message: msg.message.clone(),
+                muted,
+            }))
+            .to_text()
+        };
+        let (frame, muted_frame) = (frame(false), frame(true));
+        // other devices of the sender get it too, only the socket it came from is skipped
+        self.with_room(msg.chat.clone(), ctx, move |act| {
+            let owner = msg.message.owner.as_str();
+            let skip = msg.conn.map(|conn| (owner, conn));
+            act.broadcast(&msg.chat, skip, |member| {
+                // changes to the chat still reach those who blocked whoever made them
+                let blocked = act.blocked.get(member).is_some_and(|v| v.contains(owner));
+                if blocked && msg.message.event.is_none() {
+                    return None;
+                }
+                match act.muted.get(member).is_some_and(|v| v.contains(&msg.chat)) {
+                    true => Some(muted_frame.clone()),
+                    false => Some(frame.clone()),
+                }
+            });
+        });
+    }
+}
+
+impl Handler<SystemMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SystemMessage, ctx: &mut Context<Self>) {
+        let msg = ClientMessage {
+            conn: None,
+            sender: msg.sender,
+            chat: msg.chat,
+            message: msg.message,
+        };
+        Handler::<ClientMessage>::handle(self, msg, ctx);
+    }
+}
+
+impl Handler<Deleted> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Deleted, ctx: &mut Context<Self>) {
+        let frame = protocol::ServerFrame::Event(Box::new(protocol::Event::Deleted {
+            chat: msg.chat.clone(),
+            message: msg.message,
+        }))
+        .to_text();
+        self.with_room(msg.chat.clone(), ctx, move |act| {
+            act.broadcast(&msg.chat, None, |_| Some(frame.clone()))
+        });
+    }
+}
+
+impl Handler<ContactAdd> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ContactAdd, _: &mut Context<Self>) {
+        let event = if msg.accepted {
+            protocol::Event::ContactAccepted {
+                user: msg.requester,
+                username: msg.username,
+            }
         } else {
-            println!("no such User as : {}", msg.resiver);
+            protocol::Event::ContactRequest {
+                from: msg.requester,
+                username: msg.username,
+            }
+        };
+        self.send(
+            &msg.recipient,
+            &protocol::ServerFrame::Event(Box::new(event)).to_text(),
+        );
+    }
+}
+
+impl Handler<Block> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Block, _: &mut Context<Self>) {
+        let was_hidden = self.hidden(&msg.user, &msg.other);
+        let Some(blocked) = self.blocked.get_mut(&msg.user) else {
+            return;
+        };
+        match msg.block {
+            true => blocked.insert(msg.other.clone()),
+            false => blocked.remove(&msg.other),
+        };
+        // the two disappear for each other, or show up again
+        let hidden = self.hidden(&msg.user, &msg.other);
+        if hidden != was_hidden && self.peers(&msg.user).contains(&msg.other) {
+            self.send(&msg.user, &presence(&msg.other, !hidden));
+            self.send(&msg.other, &presence(&msg.user, !hidden));
         }
     }
 }
 
+impl Handler<Mute> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Mute, _: &mut Context<Self>) {
+        let Some(muted) = self.muted.get_mut(&msg.user) else {
+            return;
+        };
+        match msg.mute {
+            true => muted.insert(msg.chat),
+            false => muted.remove(&msg.chat),
+        };
+    }
+}
+
 impl Handler<ListUsers> for ChatServer {
     type Result = ();
 
@@ -87,3 +462,11 @@ impl Handler<ListUsers> for ChatServer {
         println!("{:#?}", self.session);
     }
 }
+
+fn presence(user: &str, online: bool) -> String {
+    protocol::ServerFrame::Event(Box::new(protocol::Event::Presence {
+        user: user.to_string(),
+        online,
+    }))
+    .to_text()
+}
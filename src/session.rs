@@ -1,13 +1,23 @@
-use crate::server;
+use crate::{
+    data,
+    error::ApiError,
+    protocol, server,
+    table::{self, Permission},
+};
 use actix::{
     fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, ContextFutureSpawner, Handler,
     StreamHandler, WrapFuture,
 };
+use actix_web::web;
 use actix_web_actors::ws;
 
 pub struct SocketSession {
+    pub id: String,
+    pub session: String,
+    pub conn: usize,
     pub username: String,
     pub addr: Addr<server::ChatServer>,
+    pub db: web::Data<dyn data::Database>,
 }
 
 impl Actor for SocketSession {
@@ -17,19 +27,30 @@ impl Actor for SocketSession {
         let addr = ctx.address();
         self.addr
             .send(server::Connect {
+                id: self.id.clone(),
                 username: self.username.clone(),
-                addr: addr.recipient(),
+                session: self.session.clone(),
+                addr: addr.clone().recipient(),
+                close: addr.recipient(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.username = res,
+                    Ok(res) => act.conn = res,
                     _ => ctx.stop(),
                 }
                 fut::ready(())
             })
             .wait(ctx);
     }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> actix::Running {
+        self.addr.do_send(server::Disconnect {
+            id: self.id.clone(),
+            conn: self.conn,
+        });
+        actix::Running::Stop
+    }
 }
 
 impl Handler<server::Message> for SocketSession {
@@ -40,6 +61,77 @@ impl Handler<server::Message> for SocketSession {
     }
 }
 
+impl Handler<server::Close> for SocketSession {
+    type Result = ();
+
+    fn handle(&mut self, _: server::Close, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some("session ended".into()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl SocketSession {
+    fn send(
+        &mut self,
+        id: String,
+        chat: Option<String>,
+        resiver: Option<String>,
+        body: String,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let db = self.db.clone();
+        let msg = table::Message::new(body, self.id.clone());
+        // store the message before it is delivered, so it can be fetched again
+        async move {
+            let chat = match (chat, resiver) {
+                (Some(chat), _) => {
+                    db.check_permission(chat.clone(), msg.owner.clone(), Permission::Send)
+                        .await?;
+                    chat
+                }
+                (None, Some(resiver)) => db.direct_chat(msg.owner.clone(), resiver).await?,
+                (None, None) => {
+                    return Err(ApiError::BadRequest("either chat or to is required".into()))
+                }
+            };
+            let chat = db.insert_to_chat(chat, msg.clone()).await?;
+            Ok((chat, msg))
+        }
+        .into_actor(self)
+        .then(|res, act, ctx| {
+            match res {
+                Ok((chat, message)) => {
+                    ctx.text(
+                        protocol::ServerFrame::Ack {
+                            id,
+                            chat: chat.clone(),
+                            message: message.id.clone(),
+                        }
+                        .to_text(),
+                    );
+                    act.addr.do_send(server::ClientMessage {
+                        conn: Some(act.conn),
+                        chat,
+                        message,
+                        sender: act.username.clone(),
+                    });
+                }
+                Err(err) => {
+                    err.log();
+                    ctx.text(
+                        protocol::ServerFrame::error(Some(id), err.code(), err.message()).to_text(),
+                    )
+                }
+            }
+            fut::ready(())
+        })
+        .wait(ctx);
+    }
+}
+
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SocketSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
@@ -51,22 +143,13 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SocketSession {
         };
 
         match msg {
-            ws::Message::Text(t) => {
-                println!("{t}");
-                let text = t.trim();
-                let parts: Vec<&str> = text.splitn(2, '/').collect();
-                if parts[0] == "LU" {
-                    self.addr.do_send(server::ListUsers);
-                } else {
-                    if parts.len() > 1 {
-                        self.addr.do_send(server::ClientMessage {
-                            resiver: parts[0].to_string(),
-                            text: parts[1].to_string(),
-                            sender: self.username.clone(),
-                        });
-                    }
+            ws::Message::Text(t) => match protocol::parse(t.trim()) {
+                Ok(protocol::ClientFrame::Send { id, chat, to, body }) => {
+                    self.send(id, chat, to, body, ctx)
                 }
-            }
+                Ok(protocol::ClientFrame::ListUsers) => self.addr.do_send(server::ListUsers),
+                Err(frame) => ctx.text(frame.to_text()),
+            },
             ws::Message::Binary(_) => println!("Unexpected binary"),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
@@ -1,24 +1,215 @@
+use crate::token;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Account {
     pub username: String,
     pub passhash: String,
-    pub session: String,
     pub picture: String,
     pub email: String,
     pub chats: Vec<String>,
+    /// Accounts from before emails were verified count as verified.
+    #[serde(default = "verified")]
+    pub verified: bool,
+    /// TOTP secret encrypted with `cryption::Secrets`, set while two factor
+    /// authentication is on.
+    #[serde(default)]
+    pub totp: Option<String>,
+    /// Secret of an enrollment that wasn't confirmed with a code yet.
+    #[serde(default)]
+    pub totp_pending: Option<String>,
+    /// Time step of the last accepted code, codes don't work twice.
+    #[serde(default)]
+    pub totp_step: Option<u64>,
+    /// `token::hash`es of the unused recovery codes.
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    /// Users who accepted a contact request of this one, or whose it accepted.
+    #[serde(default)]
+    pub contacts: Vec<String>,
+    /// Only contacts may start direct chats with the account and write into them.
+    #[serde(default)]
+    pub contacts_only: bool,
+    /// Base64 encoded key the client published for its contacts.
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Users this one doesn't want to hear from. Their messages aren't
+    /// delivered to it and they can't reach it in a direct chat.
+    #[serde(default)]
+    pub blocked: Vec<String>,
+    /// Chats whose messages arrive without a notification.
+    #[serde(default)]
+    pub muted: Vec<String>,
 }
 
+fn verified() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Session {
+    /// `token::hash` of the session id, the id itself is only known to the client
+    pub token: String,
+    pub user: String,
+    pub created: String,
+    pub last_seen: String,
+    pub user_agent: String,
+    pub ip: String,
+}
+
+/// A single use token sent to a user, like the one in the email verification mail.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Token {
+    /// `token::hash` of the token
+    pub token: String,
+    pub kind: token::Kind,
+    pub user: String,
+    pub created: String,
+    /// For email changes, the address that becomes the account's once verified.
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+/// A contact request that wasn't answered yet.
 #[derive(Deserialize, Serialize, Debug)]
+pub struct ContactRequest {
+    pub sender: String,
+    pub recipient: String,
+    pub created: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
 pub struct Chat {
+    /// Chats from before groups existed are direct chats.
+    #[serde(default)]
+    pub kind: ChatKind,
+    /// Only groups have a title, topic and avatar url, direct chats are shown
+    /// as the other member.
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub avatar: Option<String>,
     pub members: Vec<String>,
+    /// Roles of the members of a group by user id, members without one are
+    /// `Role::Member`.
+    #[serde(default)]
+    pub roles: BTreeMap<String, Role>,
+    /// Ids of the pinned messages.
+    #[serde(default)]
+    pub pinned: Vec<String>,
     pub messages: Vec<Message>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatKind {
+    /// Between two users, or a user and themself. Its members never change.
+    #[default]
+    Direct,
+    Group,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Message {
+    #[serde(default)]
+    pub id: String,
     pub date: String,
     pub text: String,
     pub owner: String,
+    /// Set once the text was removed because its owner deleted their account.
+    #[serde(default)]
+    pub deleted: bool,
+    /// Set for messages the server wrote about a change to the chat, `owner`
+    /// is who made the change and `text` describes it.
+    #[serde(default)]
+    pub event: Option<ChatEvent>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can read but not write.
+    ReadOnly,
+    #[default]
+    Member,
+    /// Manages the group and its members, except for other admins.
+    Admin,
+    /// Created the group, or was handed it. There is one per group.
+    Owner,
+}
+
+/// What a role may or may not do in a chat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Send,
+    Invite,
+    Kick,
+    Rename,
+    Pin,
+    DeleteOthers,
+    ManageRoles,
+}
+
+impl Role {
+    pub fn can(self, permission: Permission) -> bool {
+        match permission {
+            Permission::Send => self >= Role::Member,
+            Permission::Invite
+            | Permission::Kick
+            | Permission::Rename
+            | Permission::Pin
+            | Permission::DeleteOthers => self >= Role::Admin,
+            Permission::ManageRoles => self == Role::Owner,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatEvent {
+    Created,
+    /// The title, topic or avatar of the group changed.
+    Updated,
+    Added {
+        user: String,
+    },
+    Removed {
+        user: String,
+    },
+    Left,
+    RoleChanged {
+        user: String,
+        role: Role,
+    },
+    Pinned {
+        message: String,
+    },
+    Unpinned {
+        message: String,
+    },
+}
+
+/// Owner of messages whose account was deleted.
+pub const DELETED_USER: &str = "user:deleted";
+
+impl Message {
+    pub fn new(text: String, owner: String) -> Message {
+        Message {
+            id: uuid::Uuid::new_v4().to_string(),
+            date: chrono::Utc::now().to_rfc3339(),
+            text,
+            owner,
+            deleted: false,
+            event: None,
+        }
+    }
+    pub fn event(event: ChatEvent, text: String, owner: String) -> Message {
+        Message {
+            event: Some(event),
+            ..Message::new(text, owner)
+        }
+    }
 }
@@ -0,0 +1,61 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Random bytes in every token, 256 bits of entropy.
+pub const BYTES: usize = 32;
+
+/// Tokens carry their kind as a prefix, so one can't be used in place of another
+/// and a leaked token is easy to recognize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Session,
+    Invite,
+    ApiKey,
+    Reset,
+    Verify,
+    TwoFactor,
+}
+
+impl Kind {
+    const ALL: [Kind; 6] = [
+        Kind::Session,
+        Kind::Invite,
+        Kind::ApiKey,
+        Kind::Reset,
+        Kind::Verify,
+        Kind::TwoFactor,
+    ];
+
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Kind::Session => "ses_",
+            Kind::Invite => "inv_",
+            Kind::ApiKey => "key_",
+            Kind::Reset => "rst_",
+            Kind::Verify => "vfy_",
+            Kind::TwoFactor => "tfa_",
+        }
+    }
+    pub fn of(token: &str) -> Option<Kind> {
+        Kind::ALL
+            .into_iter()
+            .find(|k| token.starts_with(k.prefix()))
+    }
+}
+
+/// Returns the prefix of `kind` followed by `BYTES` bytes from the OS random
+/// number generator, encoded as url safe base64 without padding.
+pub fn generate(kind: Kind) -> String {
+    let mut bytes = [0u8; BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", kind.prefix(), URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// What gets stored instead of the token. Tokens are random enough that a
+/// plain SHA-256 can't be reversed, so they can be looked up by it.
+pub fn hash(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
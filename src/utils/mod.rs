@@ -1,12 +1,28 @@
-use actix_web::HttpRequest;
+use actix_web::{http::header, HttpRequest};
+use std::fs;
 
-#[allow(dead_code)]
-pub fn pip(req: HttpRequest) {
-    println!("{}", {
-        if let Some(v) = req.peer_addr() {
-            v.ip().to_string()
-        } else {
-            "No IP ???".to_string()
-        }
-    });
+pub fn ip(req: &HttpRequest) -> String {
+    if let Some(v) = req.peer_addr() {
+        v.ip().to_string()
+    } else {
+        "No IP ???".to_string()
+    }
+}
+
+pub fn user_agent(req: &HttpRequest) -> String {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Writes a key file only its owner can read.
+pub fn write_secret(path: &str, contents: &[u8]) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)?;
+    Ok(())
 }
@@ -0,0 +1,224 @@
+use crate::config;
+use std::{collections::HashSet, sync::OnceLock};
+
+pub const USERNAME_MIN: usize = 3;
+pub const USERNAME_MAX: usize = 32;
+const EMAIL_MAX: usize = 254;
+const TITLE_MAX: usize = 64;
+const TOPIC_MAX: usize = 256;
+const AVATAR_MAX: usize = 2048;
+const PUBLIC_KEY_MAX: usize = 4096;
+
+// commonly used passwords from public breach corpora, one per line in lowercase
+const BREACHED: &str = include_str!("breached.txt");
+
+/// Returns the address trimmed and lowercased, so the same mailbox can't sign
+/// up twice with different capitalization.
+pub fn email(email: &str) -> Result<String, String> {
+    let email = email.trim().to_lowercase();
+    if email.len() > EMAIL_MAX {
+        return Err("is too long".into());
+    }
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return Err("isn't a valid email address".into());
+    };
+    let local_ok = !local.is_empty()
+        && local.len() <= 64
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .chars()
+            .all(|c| c.is_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c));
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() > 1
+        && labels.iter().all(|l| {
+            !l.is_empty()
+                && l.len() <= 63
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+                && l.chars().all(|c| c.is_alphanumeric() || c == '-')
+        });
+    if !local_ok || !domain_ok {
+        return Err("isn't a valid email address".into());
+    }
+    Ok(email)
+}
+
+/// Usernames show up in urls and mentions, so they are kept to ascii letters,
+/// digits, `_`, `-` and `.` and start with a letter or digit.
+pub fn username(username: &str) -> Result<String, String> {
+    let username = username.trim();
+    let length = username.chars().count();
+    if !(USERNAME_MIN..=USERNAME_MAX).contains(&length) {
+        return Err(format!(
+            "has to be {USERNAME_MIN} to {USERNAME_MAX} characters long"
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))
+    {
+        return Err("may only contain letters, digits, _, - and .".into());
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("has to start with a letter or digit".into());
+    }
+    Ok(username.to_string())
+}
+
+/// Group titles are shown in every chat list, so they are kept to one line.
+pub fn title(title: &str) -> Result<String, String> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err("can't be empty".into());
+    }
+    if title.chars().count() > TITLE_MAX {
+        return Err(format!("can't be longer than {TITLE_MAX} characters"));
+    }
+    if title.chars().any(char::is_control) {
+        return Err("can't contain line breaks or control characters".into());
+    }
+    Ok(title.to_string())
+}
+
+/// An empty topic removes it.
+pub fn topic(topic: &str) -> Result<String, String> {
+    let topic = topic.trim();
+    if topic.chars().count() > TOPIC_MAX {
+        return Err(format!("can't be longer than {TOPIC_MAX} characters"));
+    }
+    Ok(topic.to_string())
+}
+
+/// Avatars are linked, not uploaded. An empty url removes it.
+pub fn avatar(avatar: &str) -> Result<String, String> {
+    let avatar = avatar.trim();
+    if avatar.len() > AVATAR_MAX {
+        return Err("is too long".into());
+    }
+    if !avatar.is_empty() && !avatar.starts_with("https://") && !avatar.starts_with("http://") {
+        return Err("has to be an http or https url".into());
+    }
+    if avatar.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("isn't a valid url".into());
+    }
+    Ok(avatar.to_string())
+}
+
+/// Keys are opaque to the server, they only have to be base64. An empty key
+/// removes it.
+pub fn public_key(key: &str) -> Result<String, String> {
+    use base64::Engine;
+    let key = key.trim();
+    if key.len() > PUBLIC_KEY_MAX {
+        return Err("is too long".into());
+    }
+    if base64::engine::general_purpose::STANDARD
+        .decode(key)
+        .is_err()
+    {
+        return Err("isn't valid base64".into());
+    }
+    Ok(key.to_string())
+}
+
+pub fn password(password: &str, policy: &config::Password) -> Result<(), String> {
+    let length = password.chars().count();
+    if length < policy.min_length {
+        return Err(format!(
+            "has to be at least {} characters long",
+            policy.min_length
+        ));
+    }
+    if length > policy.max_length {
+        return Err(format!(
+            "can't be longer than {} characters",
+            policy.max_length
+        ));
+    }
+    if policy.reject_breached && breached(password) {
+        return Err("is too common, it appears in lists of breached passwords".into());
+    }
+    Ok(())
+}
+
+fn breached(password: &str) -> bool {
+    static LIST: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    LIST.get_or_init(|| BREACHED.lines().map(str::trim).collect())
+        .contains(password.to_lowercase().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emails() {
+        assert_eq!(email("  Alice@Example.COM ").unwrap(), "alice@example.com");
+        assert_eq!(
+            email("a.b+tag@mail.example.org").unwrap(),
+            "a.b+tag@mail.example.org"
+        );
+        for invalid in [
+            "",
+            "alice",
+            "alice@",
+            "@example.com",
+            "alice@example",
+            "alice@@example.com",
+            ".alice@example.com",
+            "alice.@example.com",
+            "al..ice@example.com",
+            "alice@-example.com",
+            "alice@example..com",
+            "al ice@example.com",
+        ] {
+            assert!(email(invalid).is_err(), "{invalid:?}");
+        }
+        let long = format!("{}@example.com", "a".repeat(65));
+        assert!(email(&long).is_err());
+    }
+
+    #[test]
+    fn usernames() {
+        assert_eq!(username(" Alice_1.x-y ").unwrap(), "Alice_1.x-y");
+        assert!(username("abc").is_ok());
+        assert!(username(&"a".repeat(USERNAME_MAX)).is_ok());
+        for invalid in [
+            "ab",
+            "_alice",
+            ".alice",
+            "al ice",
+            "alice!",
+            "älice",
+            "alice@home",
+        ] {
+            assert!(username(invalid).is_err(), "{invalid:?}");
+        }
+        assert!(username(&"a".repeat(USERNAME_MAX + 1)).is_err());
+    }
+
+    #[test]
+    fn passwords() {
+        let policy = config::Password::default();
+        assert!(password("correct horse", &policy).is_ok());
+        assert!(password("short", &policy).is_err());
+        assert!(password(&"a".repeat(policy.max_length + 1), &policy).is_err());
+        // lengths count characters, not bytes
+        assert!(password("ääääääää", &policy).is_ok());
+    }
+
+    #[test]
+    fn breached_passwords() {
+        let policy = config::Password::default();
+        for common in ["password", "PassWord", "iloveyou", "QWERTY123"] {
+            assert!(password(common, &policy).is_err(), "{common:?}");
+        }
+        let policy = config::Password {
+            reject_breached: false,
+            ..policy
+        };
+        assert!(password("password", &policy).is_ok());
+    }
+}